@@ -6,45 +6,48 @@ extern crate log;
 #[macro_use]
 extern crate clap;
 
+mod progress;
+mod report;
 mod termlog;
 
-use clap::{Arg, App, SubCommand};
+use clap::{Arg, App, ArgMatches, SubCommand};
+use std::time::Duration;
 
 use postgres::{Connection as PgConnection, SslMode};
 use rusqlite::Connection as SqliteConnection;
 use scurry::connection::ScurryConnection;
-use scurry::DesiredVersion;
+use scurry::connection::postgres::{self as pg_backend, Postgres, Timeouts};
+use scurry::connection::sqlite::{self as sqlite_backend, Atomicity, Sqlite};
+use scurry::backup::BackupSettings;
+use scurry::batch::{BatchOptions, BatchReport, FailurePolicy, TargetOutcome};
+use scurry::{DesiredVersion, LintFinding, Locations, Placeholders, ScanMode};
+use scurry::sql::Dialect;
 use scurry::error::ScurryError;
 
-fn get_history<T>(conn: &T) where T: ScurryConnection {
-    match conn.get_history() {
-        Ok(history) => {
-            println!("{:32} {:10} {:20} {:40}", "DATE", "VERSION", "NAME", "HASH");
-            for h in history {
-                println!("{:32} {:10} {:20} {:40}",
-                    &h.migration_date.to_rfc2822(),
-                    &h.script_version,
-                    &h.script_name,
-                    &h.script_hash);
-            }
-        },
-        Err(e) => {
-            error!("Error getting history: {:?}", e);
-        }
-    }
+fn fail(e: &ScurryError) -> ! {
+    error!("{}", report::render(e));
+    std::process::exit(report::exit_code(e));
 }
 
-fn override_versions<T>(conn: &T, desired_version: DesiredVersion) where T: ScurryConnection {
-
-    match conn.set_schema_level(desired_version) {
-        Ok(_) => {
-            info!("Schema level set.");
-        },
-        Err(e) => {
-            error!("Could not set schema level: {:?}", e);
-            std::process::exit(1);
-        }
+fn get_history<T>(conn: &T) -> Result<(), ScurryError> where T: ScurryConnection {
+    let history = try!(conn.get_history());
+    println!("{:32} {:10} {:20} {:8} {:40}", "DATE", "VERSION", "NAME", "STATUS", "HASH");
+    for h in history {
+        println!("{:32} {:10} {:20} {:8} {:40}",
+            &h.migration_date.to_rfc2822(),
+            &h.script_version,
+            &h.script_name,
+            h.status.name(),
+            &h.script_hash);
     }
+    Ok(())
+}
+
+fn override_versions<T>(conn: &T, desired_version: DesiredVersion) -> Result<(), ScurryError>
+where T: ScurryConnection {
+    try!(conn.set_schema_level(desired_version));
+    info!("Schema level set.");
+    Ok(())
 }
 
 fn do_migration<T>(connection_type: &mut T, version: DesiredVersion) -> Result<usize, ScurryError>
@@ -60,6 +63,382 @@ where T: ScurryConnection {
     connection_type.migrate(version)
 }
 
+fn dump_schema<T>(conn: &T, output: Option<&str>) -> Result<(), ScurryError>
+where T: ScurryConnection {
+    let schema = try!(conn.schema());
+    match output {
+        Some(path) => schema.write_to(path),
+        None => {
+            print!("{}", schema);
+            Ok(())
+        }
+    }
+}
+
+/// Prints each difference and exits with `EXIT_CONSISTENCY` if there were any.
+fn check_drift<T>(conn: &T) -> Result<(), ScurryError> where T: ScurryConnection {
+    let differences = try!(conn.drift());
+    for d in &differences {
+        println!("{}", d);
+    }
+    if !differences.is_empty() {
+        warn!("Schema has drifted from its migrations.");
+        std::process::exit(report::EXIT_CONSISTENCY);
+    }
+    info!("No drift found.");
+    Ok(())
+}
+
+/// Writes a baseline for the migrations up to `version`, optionally removing the files it
+/// replaces.
+fn squash<T>(conn: &T,
+             version: DesiredVersion,
+             matches: &ArgMatches,
+             migrations_dir: &str)
+             -> Result<(), ScurryError>
+where T: ScurryConnection {
+    let baseline = try!(conn.squash(version));
+    let path = match matches.value_of("output") {
+        Some(path) => path.to_string(),
+        None => {
+            let name = baseline.file_name(matches.value_of("name").unwrap_or("baseline"));
+            format!("{}/{}", migrations_dir.trim_end_matches('/'), name)
+        }
+    };
+    for replaced in &baseline.replaced {
+        if replaced == &path {
+            continue;
+        }
+        if matches.is_present("remove-squashed") {
+            try!(std::fs::remove_file(replaced));
+            info!("Removed {}", replaced);
+        } else {
+            warn!("{} is squashed into the baseline and must be removed", replaced);
+        }
+    }
+    try!(baseline.write_to(&path));
+    info!("Squashed {} migrations into {}", baseline.squashes.len(), path);
+    Ok(())
+}
+
+/// Prints each finding and exits with `EXIT_LINT` if there were any.
+fn report_findings(findings: &[LintFinding]) {
+    for finding in findings {
+        println!("{}", finding);
+    }
+    if !findings.is_empty() {
+        warn!("{} lint findings.", findings.len());
+        std::process::exit(report::EXIT_LINT);
+    }
+    info!("No lint findings.");
+}
+
+/// Runs the subcommand shared by every backend and exits with the matching status code.
+fn run_subcommand<T>(conn: &mut T,
+                     matches: &ArgMatches,
+                     version: DesiredVersion,
+                     migrations_dir: &str)
+                     -> !
+where T: ScurryConnection {
+    let result = if let Some(_) = matches.subcommand_matches("migrate") {
+        match do_migration(conn, version) {
+            Ok(0) => {
+                info!("Nothing to migrate.");
+                std::process::exit(report::EXIT_NOTHING_TO_DO);
+            },
+            Ok(applied) => {
+                info!("Applied {} migrations.", applied);
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    } else if let Some(_) = matches.subcommand_matches("history") {
+        get_history(conn)
+    } else if let Some(_) = matches.subcommand_matches("mark") {
+        override_versions(conn, version)
+    } else if let Some(_) = matches.subcommand_matches("lint") {
+        conn.lint(version).map(|findings| report_findings(&findings))
+    } else if let Some(matches) = matches.subcommand_matches("dump-schema") {
+        dump_schema(conn, matches.value_of("output"))
+    } else if let Some(_) = matches.subcommand_matches("drift") {
+        check_drift(conn)
+    } else if let Some(matches) = matches.subcommand_matches("squash") {
+        squash(conn, version, matches, migrations_dir)
+    } else {
+        error!("{}", matches.usage());
+        std::process::exit(report::EXIT_FAILURE);
+    };
+    match result {
+        Ok(_) => std::process::exit(report::EXIT_OK),
+        Err(e) => fail(&e),
+    }
+}
+
+/// The `--parallel` and `--continue-on-error` settings given to `migrate`.
+fn batch_options(matches: &ArgMatches) -> BatchOptions {
+    let parallelism = match value_t!(matches, "parallel", usize) {
+        Ok(n) => n,
+        Err(e) => e.exit(),
+    };
+    BatchOptions {
+        parallelism: parallelism,
+        policy: if matches.is_present("continue-on-error") {
+            FailurePolicy::ContinueOnError
+        } else {
+            FailurePolicy::FailFast
+        },
+    }
+}
+
+/// Prints the per-target results and exits with the status of the first failure, if any.
+fn finish_batch(batch: &BatchReport) -> ! {
+    print!("{}", batch);
+    for r in batch.failures() {
+        match r.outcome {
+            TargetOutcome::Failed(ref e) => fail(e),
+            TargetOutcome::Panicked(_) => std::process::exit(report::EXIT_MIGRATION),
+            _ => std::process::exit(report::EXIT_FAILURE),
+        }
+    }
+    if batch.applied() == 0 {
+        info!("Nothing to migrate.");
+        std::process::exit(report::EXIT_NOTHING_TO_DO);
+    }
+    info!("Applied {} migrations across {} targets.", batch.applied(), batch.results.len());
+    std::process::exit(report::EXIT_OK);
+}
+
+/// Connects to `url` and configures the connection the same way as for a single database, minus
+/// the progress listener and schema dump, which don't make sense across many.
+fn open_postgres(url: &str,
+                 safeguards: (Timeouts, u32),
+                 locations: &Locations,
+                 placeholders: &Placeholders,
+                 seeds_dirs: &[String],
+                 environment: Option<&str>)
+                 -> Result<Postgres, ScurryError> {
+    let pg_conn = try!(PgConnection::connect(url, SslMode::None)
+        .map_err(|e| ScurryError::Connection(Box::new(e))));
+    let mut conn = scurry::from_postgres(pg_conn, &locations.dirs[0])
+        .with_locations(locations.clone());
+    conn = with_safeguards(conn, safeguards);
+    conn = conn.with_placeholders(placeholders.clone());
+    for dir in seeds_dirs {
+        conn = conn.with_seeds_dir(dir);
+    }
+    if let Some(env) = environment {
+        conn = conn.with_environment(env);
+    }
+    Ok(conn)
+}
+
+/// Migrates every schema named by `--schemas` or returned by `--schema-query`.
+fn migrate_schemas(pg_conn: &PgConnection,
+                   url: &str,
+                   matches: &ArgMatches,
+                   version: DesiredVersion,
+                   locations: &Locations,
+                   placeholders: &Placeholders,
+                   seeds_dirs: &[&str],
+                   environment: Option<&str>)
+                   -> ! {
+    let mut schemas: Vec<String> =
+        matches.values_of("schemas").map(|v| v.map(|s| s.into()).collect()).unwrap_or(vec![]);
+    if let Some(query) = matches.value_of("schema-query") {
+        match pg_backend::query_schemas(pg_conn, query) {
+            Ok(found) => schemas.extend(found),
+            Err(e) => fail(&e),
+        }
+    }
+    info!("Migrating {} schemas", schemas.len());
+    let url = url.to_string();
+    let safeguards = pg_safeguards(matches);
+    let locations = locations.clone();
+    let placeholders = placeholders.clone();
+    let seeds_dirs: Vec<String> = seeds_dirs.iter().map(|d| d.to_string()).collect();
+    let environment = environment.map(|e| e.to_string());
+    let fallbacks: Vec<String> = matches.values_of("fallback-schema")
+        .map(|v| v.map(|s| s.into()).collect())
+        .unwrap_or(vec![]);
+    let batch = pg_backend::migrate_schemas(schemas, version, &batch_options(matches), move || {
+        let conn = try!(open_postgres(&url,
+                                      safeguards,
+                                      &locations,
+                                      &placeholders,
+                                      &seeds_dirs,
+                                      environment.as_ref().map(|e| e.as_str())));
+        Ok(fallbacks.iter().fold(conn, |conn, schema| conn.with_fallback_schema(schema)))
+    });
+    finish_batch(&batch)
+}
+
+/// The backups requested by `migrate --backup`, stored in `--backup-dir` if given.
+fn backup_settings(matches: &ArgMatches) -> Option<BackupSettings> {
+    let migrate = match matches.subcommand_matches("migrate") {
+        Some(migrate) if migrate.is_present("backup") => migrate,
+        _ => return None,
+    };
+    let mut settings = BackupSettings::new(value_t!(migrate, "keep-backups", usize)
+        .unwrap_or_else(|e| e.exit()));
+    settings.dir = matches.value_of("backup-dir").map(|d| d.into());
+    Some(settings)
+}
+
+fn with_backups(conn: Sqlite, backups: Option<&BackupSettings>) -> Sqlite {
+    match backups {
+        Some(settings) => conn.with_backups(settings.keep),
+        None => conn,
+    }
+}
+
+/// How `migrate` commits and how long it waits for the database's write lock, from
+/// `--per-migration` and `--busy-timeout`.
+fn sqlite_locking(matches: &ArgMatches) -> (Atomicity, Duration) {
+    let migrate = match matches.subcommand_matches("migrate") {
+        Some(migrate) => migrate,
+        None => return (Atomicity::WholeRun, Duration::from_secs(5)),
+    };
+    let atomicity = if migrate.is_present("per-migration") {
+        Atomicity::PerMigration
+    } else {
+        Atomicity::WholeRun
+    };
+    let timeout = value_t!(migrate, "busy-timeout", u64).unwrap_or_else(|e| e.exit());
+    (atomicity, Duration::from_millis(timeout))
+}
+
+fn with_locking(conn: Sqlite, locking: (Atomicity, Duration)) -> Sqlite {
+    let (atomicity, timeout) = locking;
+    conn.with_atomicity(atomicity).with_busy_timeout(timeout)
+}
+
+/// The timeouts and lock retries given to Postgres `migrate`.
+fn pg_safeguards(migrate: &ArgMatches) -> (Timeouts, u32) {
+    let timeout = |name: &str| {
+        migrate.value_of(name).map(|value| match pg_backend::parse_timeout(value) {
+            Some(timeout) => timeout,
+            None => {
+                error!("Invalid --{} '{}', expected e.g. 500ms, 5s or 2min", name, value);
+                std::process::exit(report::EXIT_FAILURE);
+            }
+        })
+    };
+    let timeouts = Timeouts {
+        lock: timeout("lock-timeout"),
+        statement: timeout("statement-timeout"),
+        idle_in_transaction: timeout("idle-in-transaction-timeout"),
+    };
+    let retries = value_t!(migrate, "lock-retries", u32).unwrap_or_else(|e| e.exit());
+    (timeouts, retries)
+}
+
+fn with_safeguards(conn: Postgres, safeguards: (Timeouts, u32)) -> Postgres {
+    let (timeouts, retries) = safeguards;
+    conn.with_timeouts(timeouts).with_lock_retries(retries, Duration::from_secs(1))
+}
+
+/// Opens the database at `path` and configures the connection the same way as for a single
+/// database, minus the progress listener and schema dump.
+fn open_sqlite(path: &str,
+               backups: Option<&BackupSettings>,
+               locking: (Atomicity, Duration),
+               locations: &Locations,
+               placeholders: &Placeholders,
+               seeds_dirs: &[String],
+               environment: Option<&str>)
+               -> Result<Sqlite, ScurryError> {
+    let sqlite_conn = try!(SqliteConnection::open(path)
+        .map_err(|e| ScurryError::Connection(Box::new(e))));
+    let mut conn = scurry::from_sqlite(sqlite_conn, &locations.dirs[0])
+        .with_locations(locations.clone());
+    conn = with_backups(conn, backups);
+    conn = with_locking(conn, locking);
+    if let Some(dir) = backups.and_then(|b| b.dir.as_ref()) {
+        conn = conn.with_backup_dir(dir);
+    }
+    conn = conn.with_placeholders(placeholders.clone());
+    for dir in seeds_dirs {
+        conn = conn.with_seeds_dir(dir);
+    }
+    if let Some(env) = environment {
+        conn = conn.with_environment(env);
+    }
+    Ok(conn)
+}
+
+/// Migrates every database file named by `--files` or listed in `--file-list`.
+fn migrate_files(matches: &ArgMatches,
+                 version: DesiredVersion,
+                 locations: &Locations,
+                 placeholders: &Placeholders,
+                 seeds_dirs: &[&str],
+                 environment: Option<&str>)
+                 -> ! {
+    let migrate = match matches.subcommand_matches("migrate") {
+        Some(migrate) => migrate,
+        None => {
+            error!("--files and --file-list can only be used with migrate");
+            std::process::exit(report::EXIT_FAILURE);
+        }
+    };
+    let mut files = vec![];
+    for spec in matches.values_of("files").map(|v| v.collect()).unwrap_or(vec![]) {
+        match sqlite_backend::find_databases(spec) {
+            Ok(found) => files.extend(found),
+            Err(e) => fail(&e),
+        }
+    }
+    if let Some(list) = matches.value_of("file-list") {
+        match sqlite_backend::read_database_list(list) {
+            Ok(listed) => files.extend(listed),
+            Err(e) => fail(&e),
+        }
+    }
+    info!("Migrating {} databases", files.len());
+    let backups = backup_settings(matches);
+    let locking = sqlite_locking(matches);
+    let locations = locations.clone();
+    let placeholders = placeholders.clone();
+    let seeds_dirs: Vec<String> = seeds_dirs.iter().map(|d| d.to_string()).collect();
+    let environment = environment.map(|e| e.to_string());
+    let batch = sqlite_backend::migrate_files(files, version, &batch_options(migrate), move |path| {
+        open_sqlite(path,
+                    backups.as_ref(),
+                    locking,
+                    &locations,
+                    &placeholders,
+                    &seeds_dirs,
+                    environment.as_ref().map(|e| e.as_str()))
+    });
+    finish_batch(&batch)
+}
+
+/// The `--dump-schema` file given to `migrate`, if any.
+fn schema_dump_path<'a>(matches: &'a ArgMatches) -> Option<&'a str> {
+    matches.subcommand_matches("migrate").and_then(|m| m.value_of("dump-schema"))
+}
+
+/// Placeholder values from `--placeholder-file`, overridden by any given with `--placeholder`.
+fn parse_placeholders(matches: &ArgMatches) -> Placeholders {
+    let mut placeholders = Placeholders::new();
+    placeholders.set_lenient(matches.is_present("lenient-placeholders"));
+    for path in matches.values_of("placeholder-file").map(|v| v.collect()).unwrap_or(vec![]) {
+        if let Err(e) = placeholders.read_file(path) {
+            fail(&e);
+        }
+    }
+    for pair in matches.values_of("placeholder").map(|v| v.collect()).unwrap_or(vec![]) {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) if !name.is_empty() => placeholders.insert(name, value),
+            _ => {
+                error!("Invalid placeholder '{}', expected KEY=VALUE", pair);
+                std::process::exit(report::EXIT_FAILURE);
+            }
+        }
+    }
+    placeholders
+}
 
 fn main() {
     termlog::init().unwrap();
@@ -69,16 +448,64 @@ fn main() {
             .short("d")
             .long("dir")
             .value_name("MIGRATIONS")
-            .help("Path to migrations.  Default: ./migrations")
-            .takes_value(true))
+            .help("Path to migrations.  May be repeated.  Default: ./migrations")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("recursive")
+            .short("R")
+            .long("recursive")
+            .help("Also read migrations from subdirectories"))
+        .arg(Arg::with_name("warn-invalid")
+            .long("warn-invalid")
+            .help("Warn about and skip unparsable or duplicated migration files instead of \
+                   failing"))
         .arg(Arg::with_name("version")
             .short("r")
             .long("revision")
             .value_name("VERSION")
             .help("Version to migrate to.  Defaults to latest")
             .takes_value(true))
+        .arg(Arg::with_name("placeholder")
+            .long("placeholder")
+            .value_name("KEY=VALUE")
+            .help("Value substituted for ${KEY} in migration scripts.  May be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("placeholder-file")
+            .long("placeholder-file")
+            .value_name("FILE")
+            .help("File of KEY = VALUE placeholder lines.  May be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("lenient-placeholders")
+            .long("lenient-placeholders")
+            .help("Leave ${KEY} references without a value as they are instead of failing"))
+        .arg(Arg::with_name("seeds")
+            .long("seeds")
+            .value_name("SEEDS")
+            .help("Directory of seed scripts applied after migrating.  May be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("environment")
+            .short("e")
+            .long("env")
+            .value_name("ENV")
+            .help("Also apply the seeds in the ENV subdirectory of each seeds directory")
+            .takes_value(true))
         .subcommand(SubCommand::with_name("revisions")
             .about("List available versions"))
+        .subcommand(SubCommand::with_name("lint")
+            .about("Check every migration for risky statements")
+            .arg(Arg::with_name("dialect")
+                .long("dialect")
+                .value_name("DIALECT")
+                .possible_values(&["postgres", "sqlite"])
+                .required(true)
+                .help("Database the migrations are written for")))
         .subcommand(SubCommand::with_name("postgres")
             .about("Migrate Postgres DB")
             .arg(Arg::with_name("connect")
@@ -87,12 +514,99 @@ fn main() {
                 .value_name("CONNECTION_STRING")
                 .required(true)
                 .help("Connection string for Postgres DB"))
+            .arg(Arg::with_name("scratch-url")
+                .long("scratch-url")
+                .value_name("CONNECTION_STRING")
+                .takes_value(true)
+                .help("Empty database drift and squash replay migrations in"))
             .subcommand(SubCommand::with_name("mark")
                 .about("Set schema version without running migrations"))
             .subcommand(SubCommand::with_name("migrate")
-                .about("Migrate schema"))
+                .about("Migrate schema")
+                .arg(Arg::with_name("dump-schema")
+                    .long("dump-schema")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .help("Write the resulting schema to FILE after migrating"))
+                .arg(Arg::with_name("schemas")
+                    .long("schemas")
+                    .value_name("SCHEMAS")
+                    .takes_value(true)
+                    .multiple(true)
+                    .use_delimiter(true)
+                    .help("Migrate each of these comma-separated schemas instead of the default"))
+                .arg(Arg::with_name("schema-query")
+                    .long("schema-query")
+                    .value_name("SQL")
+                    .takes_value(true)
+                    .help("Also migrate every schema named in the first column of SQL's results"))
+                .arg(Arg::with_name("fallback-schema")
+                    .long("fallback-schema")
+                    .value_name("SCHEMA")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Put SCHEMA on the search path after each schema.  May be repeated"))
+                .arg(Arg::with_name("parallel")
+                    .long("parallel")
+                    .value_name("N")
+                    .takes_value(true)
+                    .default_value("1")
+                    .help("Migrate up to N schemas at once"))
+                .arg(Arg::with_name("continue-on-error")
+                    .long("continue-on-error")
+                    .help("Keep migrating the remaining schemas after one fails"))
+                .arg(Arg::with_name("lock-timeout")
+                    .long("lock-timeout")
+                    .value_name("TIME")
+                    .takes_value(true)
+                    .help("Give up on a lock after TIME, e.g. 5s, instead of queueing behind it"))
+                .arg(Arg::with_name("statement-timeout")
+                    .long("statement-timeout")
+                    .value_name("TIME")
+                    .takes_value(true)
+                    .help("Cancel any statement that runs longer than TIME"))
+                .arg(Arg::with_name("idle-in-transaction-timeout")
+                    .long("idle-in-transaction-timeout")
+                    .value_name("TIME")
+                    .takes_value(true)
+                    .help("End the session if the transaction sits idle for longer than TIME"))
+                .arg(Arg::with_name("lock-retries")
+                    .long("lock-retries")
+                    .value_name("N")
+                    .takes_value(true)
+                    .default_value("3")
+                    .help("Start over up to N times, with growing pauses, after a lock timeout")))
             .subcommand(SubCommand::with_name("history")
                 .about("List installed versions"))
+            .subcommand(SubCommand::with_name("lint")
+                .about("Check pending migrations for risky statements"))
+            .subcommand(SubCommand::with_name("dump-schema")
+                .about("Print the current schema as SQL")
+                .arg(Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .help("Write the schema to FILE instead of stdout")))
+            .subcommand(SubCommand::with_name("drift")
+                .about("Compare the schema with what the installed migrations produce"))
+            .subcommand(SubCommand::with_name("squash")
+                .about("Replace the migrations up to --revision with a single baseline")
+                .arg(Arg::with_name("name")
+                    .long("name")
+                    .value_name("NAME")
+                    .takes_value(true)
+                    .help("Name of the baseline migration.  Default: baseline"))
+                .arg(Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .help("Write the baseline to FILE instead of the first migrations directory"))
+                .arg(Arg::with_name("remove-squashed")
+                    .long("remove-squashed")
+                    .help("Delete the migration files the baseline replaces")))
         )
         .subcommand(SubCommand::with_name("sqlite")
             .about("Migrate Sqlite DB")
@@ -100,17 +614,113 @@ fn main() {
                 .short("p")
                 .long("path")
                 .value_name("PATH")
-                .required(true)
+                .required_unless_one(&["files", "file-list"])
+                .conflicts_with_all(&["files", "file-list"])
                 .help("Path to Sqlite DB"))
+            .arg(Arg::with_name("files")
+                .long("files")
+                .value_name("FILES")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Migrate every database in a directory, or matching a pattern such as \
+                       'data/*.db'.  May be repeated"))
+            .arg(Arg::with_name("file-list")
+                .long("file-list")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Migrate every database listed, one path per line, in FILE"))
+            .arg(Arg::with_name("backup-dir")
+                .long("backup-dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .help("Directory backups are kept in.  Default: next to the database"))
             .subcommand(SubCommand::with_name("mark")
                 .about("Set schema version without running migrations"))
             .subcommand(SubCommand::with_name("migrate")
-                .about("Migrate schema"))
+                .about("Migrate schema")
+                .arg(Arg::with_name("dump-schema")
+                    .long("dump-schema")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .help("Write the resulting schema to FILE after migrating"))
+                .arg(Arg::with_name("parallel")
+                    .long("parallel")
+                    .value_name("N")
+                    .takes_value(true)
+                    .default_value("1")
+                    .help("Migrate up to N databases at once"))
+                .arg(Arg::with_name("continue-on-error")
+                    .long("continue-on-error")
+                    .help("Keep migrating the remaining databases after one fails"))
+                .arg(Arg::with_name("backup")
+                    .long("backup")
+                    .help("Back each database up before migrating it"))
+                .arg(Arg::with_name("keep-backups")
+                    .long("keep-backups")
+                    .value_name("N")
+                    .takes_value(true)
+                    .default_value("5")
+                    .help("Delete all but the N most recent backups, or none if 0"))
+                .arg(Arg::with_name("per-migration")
+                    .long("per-migration")
+                    .help("Commit after each migration instead of once for the whole run"))
+                .arg(Arg::with_name("busy-timeout")
+                    .long("busy-timeout")
+                    .value_name("MS")
+                    .takes_value(true)
+                    .default_value("5000")
+                    .help("How long to wait for another process to release the database")))
+            .subcommand(SubCommand::with_name("backups")
+                .about("List backups of the database, oldest first"))
+            .subcommand(SubCommand::with_name("restore")
+                .about("Replace the database with a backup")
+                .arg(Arg::with_name("from")
+                    .long("from")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .help("Backup to restore.  Default: the most recent one")))
             .subcommand(SubCommand::with_name("history")
                 .about("List installed versions"))
+            .subcommand(SubCommand::with_name("lint")
+                .about("Check pending migrations for risky statements"))
+            .subcommand(SubCommand::with_name("dump-schema")
+                .about("Print the current schema as SQL")
+                .arg(Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .help("Write the schema to FILE instead of stdout")))
+            .subcommand(SubCommand::with_name("drift")
+                .about("Compare the schema with what the installed migrations produce"))
+            .subcommand(SubCommand::with_name("squash")
+                .about("Replace the migrations up to --revision with a single baseline")
+                .arg(Arg::with_name("name")
+                    .long("name")
+                    .value_name("NAME")
+                    .takes_value(true)
+                    .help("Name of the baseline migration.  Default: baseline"))
+                .arg(Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .help("Write the baseline to FILE instead of the first migrations directory"))
+                .arg(Arg::with_name("remove-squashed")
+                    .long("remove-squashed")
+                    .help("Delete the migration files the baseline replaces")))
         ).get_matches();
 
-    let migrations_dir = matches.value_of("migrations").unwrap_or("./migrations");
+    let mut locations = Locations::new("./migrations");
+    if let Some(dirs) = matches.values_of("migrations") {
+        locations.dirs = dirs.map(|d| d.into()).collect();
+    }
+    locations.recursive = matches.is_present("recursive");
+    if matches.is_present("warn-invalid") {
+        locations.mode = ScanMode::Warn;
+    }
+    let migrations_dir = locations.dirs[0].clone();
     let version = match matches.value_of("version") {
         Some(v) => {
             if v == "latest" {
@@ -121,58 +731,122 @@ fn main() {
         },
         None => DesiredVersion::Latest,
     };
+    let placeholders = parse_placeholders(&matches);
+    let seeds_dirs: Vec<&str> = matches.values_of("seeds").map(|v| v.collect()).unwrap_or(vec![]);
+    let environment = matches.value_of("environment");
     if let Some(matches) = matches.subcommand_matches("postgres") {
-        let pg_conn = match PgConnection::connect(matches.value_of("connect").unwrap(), SslMode::None) {
+        let url = matches.value_of("connect").unwrap();
+        let pg_conn = match PgConnection::connect(url, SslMode::None) {
             Ok(conn) => conn,
             Err(e) => {
-                error!("Failed connecting to postgres: {:?}", e);
-                std::process::exit(1);
+                error!("Failed connecting to postgres: {}", e);
+                std::process::exit(report::EXIT_CONNECTION);
             }
         };
-        let mut conn = scurry::from_postgres(pg_conn, migrations_dir);
-
-        if let Some(_) = matches.subcommand_matches("migrate") {
-            if let Err(e) = do_migration(&mut conn, version) {
-                error!("Failed to migrate: {:?}", e);
-                std::process::exit(1);
+        if let Some(migrate) = matches.subcommand_matches("migrate") {
+            if migrate.is_present("schemas") || migrate.is_present("schema-query") {
+                migrate_schemas(&pg_conn,
+                                url,
+                                migrate,
+                                version,
+                                &locations,
+                                &placeholders,
+                                &seeds_dirs,
+                                environment);
             }
-
-        } else if let Some(_) = matches.subcommand_matches("history") {
-            get_history(&conn);
-        } else if let Some(_) = matches.subcommand_matches("mark") {
-            override_versions(&conn, version);
         }
+        let mut conn = scurry::from_postgres(pg_conn, &migrations_dir)
+            .with_locations(locations.clone())
+            .with_listener(progress::Progress::new());
+        if let Some(migrate) = matches.subcommand_matches("migrate") {
+            conn = with_safeguards(conn, pg_safeguards(migrate));
+        }
+        conn = conn.with_placeholders(placeholders.clone());
+        if let Some(path) = schema_dump_path(matches) {
+            conn = conn.with_schema_dump(path);
+        }
+        if let Some(scratch_url) = matches.value_of("scratch-url") {
+            conn = conn.with_scratch_database(scratch_url);
+        }
+        for dir in &seeds_dirs {
+            conn = conn.with_seeds_dir(dir);
+        }
+        if let Some(env) = environment {
+            conn = conn.with_environment(env);
+        }
+        run_subcommand(&mut conn, matches, version, &migrations_dir);
     } else if let Some(matches) = matches.subcommand_matches("sqlite") {
-        let sqlite_conn = match SqliteConnection::open(matches.value_of("path").unwrap()) {
+        if matches.is_present("files") || matches.is_present("file-list") {
+            migrate_files(matches, version, &locations, &placeholders, &seeds_dirs, environment);
+        }
+        let path = matches.value_of("path").unwrap();
+        let sqlite_conn = match SqliteConnection::open(path) {
             Ok(conn) => conn,
             Err(e) => {
-                error!("Failed opening sqlite db: {:?}", e);
-                std::process::exit(1);
+                error!("Failed opening sqlite db: {}", e);
+                std::process::exit(report::EXIT_CONNECTION);
             }
         };
-        let mut conn = scurry::from_sqlite(sqlite_conn, migrations_dir);
-        if let Some(_) = matches.subcommand_matches("migrate") {
-            if let Err(e) = do_migration(&mut conn, version) {
-                error!("Failed migration: {:?}", e);
-                std::process::exit(1);
+        let mut conn = scurry::from_sqlite(sqlite_conn, &migrations_dir)
+            .with_locations(locations.clone())
+            .with_listener(progress::Progress::new());
+        conn = conn.with_placeholders(placeholders.clone());
+        if let Some(path) = schema_dump_path(matches) {
+            conn = conn.with_schema_dump(path);
+        }
+        for dir in &seeds_dirs {
+            conn = conn.with_seeds_dir(dir);
+        }
+        if let Some(env) = environment {
+            conn = conn.with_environment(env);
+        }
+        conn = with_backups(conn, backup_settings(matches).as_ref());
+        conn = with_locking(conn, sqlite_locking(matches));
+        if let Some(dir) = matches.value_of("backup-dir") {
+            conn = conn.with_backup_dir(dir);
+        }
+        if let Some(_) = matches.subcommand_matches("backups") {
+            match conn.backups() {
+                Ok(backups) => {
+                    for b in backups {
+                        println!("{}", b);
+                    }
+                    std::process::exit(report::EXIT_OK);
+                }
+                Err(e) => fail(&e),
+            }
+        } else if let Some(restore) = matches.subcommand_matches("restore") {
+            match conn.restore(restore.value_of("from")) {
+                Ok(backup) => {
+                    info!("Restored {} from {}", path, backup);
+                    std::process::exit(report::EXIT_OK);
+                }
+                Err(e) => fail(&e),
             }
-        } else if let Some(_) = matches.subcommand_matches("history") {
-            get_history(&conn);
-        } else if let Some(_) = matches.subcommand_matches("mark") {
-            override_versions(&conn, version);
         }
+        run_subcommand(&mut conn, matches, version, &migrations_dir);
     } else if let Some(_) = matches.subcommand_matches("revisions") {
-        match scurry::get_available_versions(migrations_dir) {
-            Ok(versions) =>  {
-                println!("{:10} {:20} {:40}", "VERSION", "NAME", "HASH");
+        match scurry::scan_migrations(&locations, None) {
+            Ok((versions, scan_report)) =>  {
+                println!("{:10} {:20} {:10} {:40}", "VERSION", "NAME", "DIALECT", "HASH");
                 for v in versions {
-                    println!("{:10} {:20} {:40}", &v.version, &v.name, &v.hash);
+                    let dialect = v.dialect.map(|d| d.tag()).unwrap_or("-");
+                    println!("{:10} {:20} {:10} {:40}", &v.version, &v.name, dialect, &v.hash);
+                }
+                for line in scan_report.to_string().lines() {
+                    warn!("{}", line);
+                }
+                if scan_report.has_errors() && locations.mode == ScanMode::Strict {
+                    std::process::exit(report::EXIT_FAILURE);
                 }
             },
-            Err(e) => {
-                error!("Error reading versions: {:?}", e);
-                std::process::exit(1);
-            }
+            Err(e) => fail(&e),
+        }
+    } else if let Some(matches) = matches.subcommand_matches("lint") {
+        let dialect = Dialect::from_tag(matches.value_of("dialect").unwrap()).unwrap();
+        match scurry::lint_migrations(&locations, dialect) {
+            Ok(findings) => report_findings(&findings),
+            Err(e) => fail(&e),
         }
     } else {
         unreachable!();
@@ -0,0 +1,51 @@
+use std::time::Duration;
+use scurry::{MigrationListener, MigrationSummary, Version};
+use scurry::error::ScurryError;
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+/// Prints a line per applied migration with a running count and its timing.
+pub struct Progress {
+    total: usize,
+    done: usize,
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        Progress { total: 0, done: 0 }
+    }
+}
+
+impl MigrationListener for Progress {
+    fn before_migrate(&mut self, pending: &[&Version]) -> Result<(), ScurryError> {
+        self.total = pending.len();
+        self.done = 0;
+        Ok(())
+    }
+
+    fn after_each(&mut self, version: &Version, duration: Duration) {
+        self.done += 1;
+        info!("[{}/{}] {} {} ({} ms)",
+              self.done,
+              self.total,
+              &version.version,
+              &version.name,
+              millis(duration));
+    }
+
+    fn on_error(&mut self, version: &Version, _err: &ScurryError) {
+        error!("[{}/{}] {} {} failed",
+               self.done + 1,
+               self.total,
+               &version.version,
+               &version.name);
+    }
+
+    fn after_migrate(&mut self, summary: &MigrationSummary) {
+        if let Some(ref v) = summary.to_version {
+            info!("Schema at version {} after {} ms", v, millis(summary.duration));
+        }
+    }
+}
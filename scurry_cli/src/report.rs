@@ -0,0 +1,94 @@
+use scurry::error::ScurryError;
+
+/// Everything went as planned.
+pub const EXIT_OK: i32 = 0;
+/// Unclassified failure: bad arguments, unreadable migrations directory, etc.
+pub const EXIT_FAILURE: i32 = 1;
+/// The database could not be reached or opened.
+pub const EXIT_CONNECTION: i32 = 2;
+/// The installed history or the schema has drifted from the migrations directory.
+pub const EXIT_CONSISTENCY: i32 = 3;
+/// A migration script failed to apply.
+pub const EXIT_MIGRATION: i32 = 4;
+/// Gave up waiting on a lock held by another session.
+pub const EXIT_LOCK_TIMEOUT: i32 = 5;
+/// The schema was already at the requested version.
+pub const EXIT_NOTHING_TO_DO: i32 = 6;
+/// `lint` found risky statements in the migrations it checked.
+pub const EXIT_LINT: i32 = 7;
+
+/// Maps an error to the exit code the CLI should terminate with.
+pub fn exit_code(e: &ScurryError) -> i32 {
+    match *e {
+        ScurryError::Io(_) |
+        ScurryError::Parse(_) |
+        ScurryError::InvalidFileName { .. } |
+        ScurryError::MissingPlaceholders { .. } |
+        ScurryError::DuplicateVersion { .. } |
+        ScurryError::MissingDependency { .. } |
+        ScurryError::DependencyCycle(_) |
+        ScurryError::InvalidMigrations(_) |
+        ScurryError::MissingSchema(_) |
+        ScurryError::MissingCodeMigration(_) |
+        ScurryError::MissingScratchDatabase |
+        ScurryError::Aborted(_) |
+        ScurryError::NothingToSquash |
+        ScurryError::NoBackups(_) => EXIT_FAILURE,
+        ScurryError::Consistency(_) |
+        ScurryError::MisplacedMetadata(_) => EXIT_CONSISTENCY,
+        ScurryError::Connection(_) |
+        ScurryError::MissingDatabase(_) => EXIT_CONNECTION,
+        ScurryError::LockTimeout(_) => EXIT_LOCK_TIMEOUT,
+        ScurryError::Sql(_) |
+        ScurryError::ForeignKeyViolations(_) => EXIT_MIGRATION,
+        ScurryError::MigrationFailed { ref cause, .. } |
+        ScurryError::CallbackFailed { ref cause, .. } => {
+            match exit_code(cause) {
+                EXIT_LOCK_TIMEOUT => EXIT_LOCK_TIMEOUT,
+                _ => EXIT_MIGRATION,
+            }
+        }
+    }
+}
+
+/// Renders an error for humans, naming the failing migration where there is one.
+pub fn render(e: &ScurryError) -> String {
+    match *e {
+        ScurryError::MigrationFailed { ref version,
+                                       ref name,
+                                       ref path,
+                                       statement_index,
+                                       span,
+                                       ref cause } => {
+            let statement = match (statement_index, span) {
+                (Some(i), Some(span)) => {
+                    format!("\n    statement: {} (line {}, column {} to line {}, column {})",
+                            i + 1,
+                            span.start.line,
+                            span.start.column,
+                            span.end.line,
+                            span.end.column)
+                }
+                (Some(i), None) => format!("\n    statement: {}", i + 1),
+                _ => String::new(),
+            };
+            format!("Migration {} ({}) failed\n    file: {}{}\n    {}",
+                    version,
+                    name,
+                    path,
+                    statement,
+                    render(cause))
+        }
+        ScurryError::CallbackFailed { callback, ref path, ref cause, .. } => {
+            format!("Callback {} failed\n    file: {}\n    {}", callback, path, render(cause))
+        }
+        ScurryError::InvalidMigrations(ref report) => {
+            let lines = report.to_string()
+                .lines()
+                .map(|l| format!("\n    {}", l))
+                .collect::<String>();
+            format!("{}{}", e, lines)
+        }
+        _ => format!("{}", e),
+    }
+}
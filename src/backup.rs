@@ -0,0 +1,120 @@
+//! Copies of a SQLite database taken with the online backup API before migrating.
+//!
+//! A failed run is rolled back, but one that succeeded, or that failed after committing some
+//! migrations under `Atomicity::PerMigration`, can only be undone from a backup taken first.
+//! Backups are consistent copies even while other connections are using the database.  They are
+//! named after the database, a short hash of its full path and the time they were taken, e.g.
+//! `app.db.3f2a9c1e.20170314T092653123.bak`, so databases with the same file name can share a
+//! backup directory and each one's backups sort oldest first.
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::UTC;
+use sha1;
+use rusqlite::{Connection, DatabaseName};
+use rusqlite::backup::Progress;
+use error::ScurryError;
+use util;
+
+const BACKUP_EXTENSION: &'static str = "bak";
+
+/// Where backups go and how many are kept.
+#[derive(Debug, Clone)]
+pub struct BackupSettings {
+    /// Directory backups are written to.  Defaults to the database's own directory.
+    pub dir: Option<String>,
+    /// How many backups of a database to keep; older ones are deleted after each new backup.
+    /// `0` keeps them all.
+    pub keep: usize,
+}
+
+impl BackupSettings {
+    pub fn new(keep: usize) -> BackupSettings {
+        BackupSettings {
+            dir: None,
+            keep: keep,
+        }
+    }
+
+    fn dir_for(&self, database: &Path) -> PathBuf {
+        match self.dir {
+            Some(ref dir) => PathBuf::from(dir),
+            None => {
+                match database.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                    _ => PathBuf::from("."),
+                }
+            }
+        }
+    }
+}
+
+/// Names of backups of `database` start with this: its file name and the first 8 hex digits of
+/// the SHA-1 of its canonical path.
+fn backup_prefix(database: &Path) -> String {
+    let name = database.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let full = database.canonicalize().unwrap_or_else(|_| database.to_path_buf());
+    let mut m = sha1::Sha1::new();
+    m.update(full.to_string_lossy().as_bytes());
+    format!("{}.{}.", name, &m.digest().to_string()[..8])
+}
+
+/// The backups of `database`, oldest first.
+pub fn list(database: &str, settings: &BackupSettings) -> Result<Vec<String>, ScurryError> {
+    let database = Path::new(database);
+    let dir = settings.dir_for(database);
+    let prefix = backup_prefix(database);
+    let suffix = format!(".{}", BACKUP_EXTENSION);
+    let mut backups = vec![];
+    if !dir.is_dir() {
+        return Ok(backups);
+    }
+    for entry in try!(fs::read_dir(&dir)) {
+        let path = try!(entry).path();
+        let is_backup = path.file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| {
+                n.len() > prefix.len() + suffix.len() && n.starts_with(&prefix) &&
+                n.ends_with(&suffix)
+            })
+            .unwrap_or(false);
+        if is_backup && path.is_file() {
+            backups.push(try!(util::path_string(&path)));
+        }
+    }
+    backups.sort();
+    Ok(backups)
+}
+
+/// Copies the main database of `conn`, stored at `database`, to a new backup and deletes the
+/// oldest backups beyond `settings.keep`.  Returns the backup's path.
+pub fn take(conn: &Connection,
+            database: &str,
+            settings: &BackupSettings)
+            -> Result<String, ScurryError> {
+    let source = Path::new(database);
+    let dir = settings.dir_for(source);
+    try!(fs::create_dir_all(&dir));
+    let name = format!("{}{}.{}",
+                       backup_prefix(source),
+                       UTC::now().format("%Y%m%dT%H%M%S%3f"),
+                       BACKUP_EXTENSION);
+    let path = try!(util::path_string(&dir.join(name)));
+    try!(conn.backup(DatabaseName::Main, &path, None));
+    info!("Backed up {} to {}", database, path);
+    if settings.keep > 0 {
+        let backups = try!(list(database, settings));
+        let excess = backups.len().saturating_sub(settings.keep);
+        for old in &backups[..excess] {
+            try!(fs::remove_file(old));
+            debug!("Removed old backup {}", old);
+        }
+    }
+    Ok(path)
+}
+
+/// Replaces the main database of `conn` with the contents of `backup`.
+pub fn restore(conn: &mut Connection, backup: &str) -> Result<(), ScurryError> {
+    try!(conn.restore(DatabaseName::Main, backup, None::<fn(Progress)>));
+    info!("Restored {}", backup);
+    Ok(())
+}
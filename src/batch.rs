@@ -0,0 +1,210 @@
+//! Runs the same migration against many targets, such as every tenant schema of a Postgres
+//! database or a directory of SQLite files, a few at a time, and reports how each one ended up.
+use std::any::Any;
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use error::ScurryError;
+
+/// What to do with the remaining targets once one has failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Don't start any more targets.  Those already running are allowed to finish.
+    FailFast,
+    ContinueOnError,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatchOptions {
+    /// How many targets are migrated at once.  Values below 1 are treated as 1.
+    pub parallelism: usize,
+    pub policy: FailurePolicy,
+}
+
+impl Default for BatchOptions {
+    fn default() -> BatchOptions {
+        BatchOptions {
+            parallelism: 1,
+            policy: FailurePolicy::FailFast,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TargetOutcome {
+    /// `applied` counts migrations and seeds; `version` is the latest installed afterwards.
+    Migrated {
+        applied: usize,
+        version: Option<String>,
+    },
+    Failed(ScurryError),
+    /// The migration panicked, e.g. in a Rust code migration, with this message.
+    Panicked(String),
+    /// Skipped because another target failed under `FailurePolicy::FailFast`.
+    NotRun,
+}
+
+#[derive(Debug)]
+pub struct TargetResult {
+    pub target: String,
+    pub outcome: TargetOutcome,
+}
+
+/// Per-target results, in the order the targets were given.
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    pub results: Vec<TargetResult>,
+}
+
+impl BatchReport {
+    pub fn failures(&self) -> Vec<&TargetResult> {
+        self.results
+            .iter()
+            .filter(|r| match r.outcome {
+                TargetOutcome::Migrated { .. } => false,
+                _ => true,
+            })
+            .collect()
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failures().is_empty()
+    }
+
+    /// The number of migrations and seeds applied across every target.
+    pub fn applied(&self) -> usize {
+        self.results
+            .iter()
+            .map(|r| match r.outcome {
+                TargetOutcome::Migrated { applied, .. } => applied,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// The targets that migrated, grouped by the version they are at.  `None` holds those with
+    /// nothing installed.
+    pub fn by_version(&self) -> BTreeMap<Option<&str>, Vec<&str>> {
+        let mut versions: BTreeMap<Option<&str>, Vec<&str>> = BTreeMap::new();
+        for r in &self.results {
+            if let TargetOutcome::Migrated { ref version, .. } = r.outcome {
+                versions.entry(version.as_ref().map(|v| v.as_str()))
+                    .or_insert_with(Vec::new)
+                    .push(&r.target);
+            }
+        }
+        versions
+    }
+}
+
+impl fmt::Display for BatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for r in &self.results {
+            match r.outcome {
+                TargetOutcome::Migrated { applied, ref version } => {
+                    try!(writeln!(f,
+                                  "{}: applied {}, at version {}",
+                                  r.target,
+                                  applied,
+                                  version.as_ref().map(|v| v.as_str()).unwrap_or("0")))
+                }
+                TargetOutcome::Failed(ref e) => try!(writeln!(f, "{}: failed: {}", r.target, e)),
+                TargetOutcome::Panicked(ref message) => {
+                    try!(writeln!(f, "{}: panicked: {}", r.target, message))
+                }
+                TargetOutcome::NotRun => try!(writeln!(f, "{}: not run", r.target)),
+            }
+        }
+        for (version, targets) in self.by_version() {
+            try!(writeln!(f,
+                          "Version {}: {}",
+                          version.unwrap_or("0"),
+                          targets.join(", ")));
+        }
+        let failures = self.failures().len();
+        if failures > 0 {
+            try!(writeln!(f, "{} of {} failed or not run", failures, self.results.len()));
+        }
+        Ok(())
+    }
+}
+
+fn panic_message(payload: &(Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(s) => (*s).into(),
+        None => {
+            payload.downcast_ref::<String>().cloned().unwrap_or_else(|| "unknown panic".into())
+        }
+    }
+}
+
+/// Calls `migrate` for each of `targets` on up to `options.parallelism` threads.  `migrate`
+/// returns how many migrations it applied and the version the target ended up at.
+pub fn run<F>(targets: Vec<String>, options: &BatchOptions, migrate: F) -> BatchReport
+    where F: Fn(&str) -> Result<(usize, Option<String>), ScurryError> + Send + Sync + 'static
+{
+    let total = targets.len();
+    let queue = Arc::new(Mutex::new(targets.into_iter().enumerate().collect::<VecDeque<_>>()));
+    let results = Arc::new(Mutex::new(Vec::with_capacity(total)));
+    let failed = Arc::new(AtomicBool::new(false));
+    let migrate = Arc::new(migrate);
+    let policy = options.policy;
+    let workers = (0..cmp::max(1, cmp::min(options.parallelism, total)))
+        .map(|_| {
+            let (queue, results) = (queue.clone(), results.clone());
+            let (failed, migrate) = (failed.clone(), migrate.clone());
+            thread::spawn(move || loop {
+                let next = queue.lock().unwrap().pop_front();
+                let (i, target) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let outcome = if policy == FailurePolicy::FailFast &&
+                                 failed.load(Ordering::SeqCst) {
+                    TargetOutcome::NotRun
+                } else {
+                    info!("Migrating {}", target);
+                    match panic::catch_unwind(AssertUnwindSafe(|| migrate(&target))) {
+                        Ok(Ok((applied, version))) => {
+                            TargetOutcome::Migrated {
+                                applied: applied,
+                                version: version,
+                            }
+                        }
+                        Ok(Err(e)) => {
+                            error!("Migrating {} failed: {}", target, e);
+                            failed.store(true, Ordering::SeqCst);
+                            TargetOutcome::Failed(e)
+                        }
+                        Err(payload) => {
+                            let message = panic_message(&*payload);
+                            error!("Migrating {} panicked: {}", target, message);
+                            failed.store(true, Ordering::SeqCst);
+                            TargetOutcome::Panicked(message)
+                        }
+                    }
+                };
+                results.lock().unwrap().push((i,
+                                              TargetResult {
+                                                  target: target,
+                                                  outcome: outcome,
+                                              }));
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        if worker.join().is_err() {
+            error!("A migration thread panicked");
+        }
+    }
+    let mut results = match Arc::try_unwrap(results) {
+        Ok(results) => results.into_inner().unwrap_or_else(|e| e.into_inner()),
+        Err(_) => unreachable!(),
+    };
+    results.sort_by_key(|&(i, _)| i);
+    BatchReport { results: results.into_iter().map(|(_, r)| r).collect() }
+}
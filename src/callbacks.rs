@@ -0,0 +1,89 @@
+//! SQL scripts with reserved names that run around migrations instead of being versioned.
+use std::fmt;
+use std::path::Path;
+use error::ScurryError;
+use placeholders::Placeholders;
+use util;
+use sql::Dialect;
+
+/// The points in a migration run where callback scripts are executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Callback {
+    /// `beforeMigrate.sql`, run once before the metadata table is locked.
+    BeforeMigrate,
+    /// `beforeEachMigrate.sql`, run before each migration in that migration's transaction.
+    BeforeEachMigrate,
+    /// `afterEachMigrate.sql`, run after each migration in that migration's transaction.
+    AfterEachMigrate,
+    /// `afterMigrate.sql`, run once after the last migration.
+    AfterMigrate,
+}
+
+const ALL_CALLBACKS: [Callback; 4] = [Callback::BeforeMigrate,
+                                      Callback::BeforeEachMigrate,
+                                      Callback::AfterEachMigrate,
+                                      Callback::AfterMigrate];
+
+impl Callback {
+    /// The reserved file stem for this callback.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Callback::BeforeMigrate => "beforeMigrate",
+            Callback::BeforeEachMigrate => "beforeEachMigrate",
+            Callback::AfterEachMigrate => "afterEachMigrate",
+            Callback::AfterMigrate => "afterMigrate",
+        }
+    }
+
+    /// Returns the callback a `.sql` file is reserved for, if any, along with the dialect it is
+    /// tagged for (e.g. `afterMigrate.postgres.sql`).
+    pub fn from_path(path: &Path) -> Option<(Callback, Option<Dialect>)> {
+        let stem = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(s) => s,
+            None => return None,
+        };
+        let (stem, dialect) = util::split_dialect(stem);
+        ALL_CALLBACKS.iter().cloned().find(|c| c.name() == stem).map(|c| (c, dialect))
+    }
+}
+
+impl fmt::Display for Callback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The callback scripts found alongside a set of migrations, with their contents.
+#[derive(Debug, Clone, Default)]
+pub struct Callbacks {
+    scripts: Vec<(Callback, String, String)>,
+}
+
+impl Callbacks {
+    pub fn add(&mut self, callback: Callback, path: String, contents: String) {
+        self.scripts.push((callback, path, contents));
+    }
+
+    /// `(path, contents)` of the scripts registered for `callback`, in the order they should run.
+    pub fn scripts(&self, callback: Callback) -> Vec<(&str, &str)> {
+        self.scripts
+            .iter()
+            .filter(|&&(c, _, _)| c == callback)
+            .map(|&(_, ref path, ref contents)| (path.as_str(), contents.as_str()))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+
+    /// A copy of these callbacks with placeholders substituted into every script.
+    pub fn substitute(&self, placeholders: &Placeholders) -> Result<Callbacks, ScurryError> {
+        let mut substituted = Callbacks::default();
+        for &(callback, ref path, ref contents) in &self.scripts {
+            let contents = try!(util::substitute(path, contents, placeholders));
+            substituted.add(callback, path.clone(), contents);
+        }
+        Ok(substituted)
+    }
+}
@@ -1,6 +1,9 @@
 use error::ScurryError;
 use versions::{Version, DesiredVersion};
 use models::ScurryMetadata;
+use lint::LintFinding;
+use schema::{Schema, SchemaDifference};
+use squash::Baseline;
 pub use util::HistoryDifferences;
 
 #[cfg(feature = "postgres")]
@@ -15,5 +18,16 @@ pub trait ScurryConnection : Sized {
     fn get_differences(&self) -> Result<Vec<HistoryDifferences>, ScurryError>;
     fn set_schema_level(&self, desired_version: DesiredVersion) -> Result<(), ScurryError>;
     fn migrate(&mut self, desired_version: DesiredVersion) -> Result<usize, ScurryError>;
+    /// Lints the migrations `migrate` would apply to reach `desired_version`.
+    fn lint(&self, desired_version: DesiredVersion) -> Result<Vec<LintFinding>, ScurryError>;
+    /// Introspects the tables, indexes, constraints and views currently in the database.
+    fn schema(&self) -> Result<Schema, ScurryError>;
+    /// Compares the database's schema with the one its installed migrations produce when run
+    /// against a scratch database, which is thrown away afterwards.  On Postgres that is the
+    /// database given to `with_scratch_database`; nothing runs against this connection's.
+    fn drift(&self) -> Result<Vec<SchemaDifference>, ScurryError>;
+    /// Runs the migrations up to `desired_version` against a scratch database, as `drift` does,
+    /// and captures the resulting schema as a baseline that can replace them.
+    fn squash(&self, desired_version: DesiredVersion) -> Result<Baseline, ScurryError>;
     fn take_connection(self) -> Self::DbConnection;
 }
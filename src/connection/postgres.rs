@@ -1,18 +1,45 @@
-use postgres::Connection;
+use postgres::{Connection, GenericConnection, TlsMode};
 use postgres::rows::Row;
-use postgres::error::Error as PgError;
+use postgres::error::{Error as PgError, SqlState};
 use error::ScurryError;
-use models::ScurryMetadata;
-use versions::{Version, DesiredVersion};
-use util::{self, HistoryDifferences};
+use models::{ScurryMetadata, MigrationStatus};
+use versions::{Version, DesiredVersion, MigrationKind};
+use std::collections::BTreeMap;
+use util::{self, HistoryDifferences, Locations};
+use sql::{self, Dialect, Statement};
+use callbacks::{Callback, Callbacks};
 use connection::ScurryConnection;
 use postgres::transaction::Transaction;
+use listener::{MigrationListener, Listeners};
+use placeholders::Placeholders;
+use lint::{self, LintFinding};
+use squash::Baseline;
+use seeds;
+use schema::{self, Schema, SchemaDifference, Column, Constraint, Index, View};
+use std::thread;
+use std::time::{Duration, Instant};
+use batch::{self, BatchOptions, BatchReport};
+use directives::Directives;
+
+const METADATA_TABLE: &'static str = "_scurry";
+
+const CURRENT_DATABASE: &'static str = "SELECT current_database();";
 
 const METADATA_EXISTS: &'static str = "
 SELECT EXISTS (
     SELECT 1 FROM pg_catalog.pg_class c
+    JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
     where c.relname = '_scurry'
-    and c.relkind = 'r');";
+    and c.relkind = 'r'
+    and n.nspname = current_schema());";
+
+/// Schema of a metadata table outside the current schema that unqualified queries would still
+/// find through the search path, as older versions used.
+const METADATA_ELSEWHERE: &'static str = "
+SELECT n.nspname FROM pg_catalog.pg_class c
+JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+WHERE c.relname = '_scurry' AND c.relkind = 'r' AND n.nspname <> current_schema()
+  AND pg_catalog.pg_table_is_visible(c.oid);";
 
 const CREATE_METADATA_TABLE: &'static str = "
 CREATE TABLE _scurry (
@@ -20,31 +47,470 @@ CREATE TABLE _scurry (
     migration_date TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
     script_hash TEXT NOT NULL,
     script_name TEXT NOT NULL,
-    script_version TEXT NOT NULL
+    script_version TEXT NOT NULL,
+    script_type TEXT NOT NULL DEFAULT 'versioned',
+    status TEXT NOT NULL DEFAULT 'applied'
 );";
 
+/// Columns added to the metadata table since it was first released, with their definitions.
+/// Tables created by older versions get them on first use.
+const METADATA_COLUMNS: &'static [(&'static str, &'static str)] =
+    &[("script_type", "TEXT NOT NULL DEFAULT 'versioned'"),
+      ("status", "TEXT NOT NULL DEFAULT 'applied'")];
+
+const METADATA_COLUMN_EXISTS: &'static str = "
+SELECT EXISTS (
+    SELECT 1 FROM pg_catalog.pg_attribute
+    WHERE attrelid = '_scurry'::regclass AND attname = $1 AND NOT attisdropped);";
+
 const INSERT_HISTORY_LINE: &'static str = "INSERT INTO _scurry(script_hash, script_name, \
-                                           script_version) values($1, $2, $3);";
+                                           script_version, status) values($1, $2, $3, $4);";
 
 const GET_ALL_REVISIONS: &'static str = "SELECT id, migration_date, script_hash, script_name, \
-                                         script_version FROM _scurry ORDER BY script_version ASC;";
+                                         script_version, status FROM _scurry \
+                                         WHERE script_type = 'versioned' \
+                                         ORDER BY script_version ASC;";
+
+const GET_SEEDS: &'static str = "SELECT script_version, script_hash FROM _scurry \
+                                 WHERE script_type = 'seed';";
+
+const DELETE_SEED: &'static str = "DELETE FROM _scurry WHERE script_type = 'seed' \
+                                   AND script_version = $1;";
+
+const INSERT_SEED: &'static str = "INSERT INTO _scurry(script_hash, script_name, script_version, \
+                                   script_type) values($1, $2, $3, 'seed');";
 
 const ACQUIRE_LOCK: &'static str = "LOCK TABLE _scurry IN ACCESS EXCLUSIVE MODE;";
 
-const DELETE_HISTORY: &'static str = "DELETE FROM _scurry;";
+const DELETE_HISTORY: &'static str = "DELETE FROM _scurry WHERE script_type = 'versioned';";
+
+const CURRENT_SCHEMA: &'static str = "SELECT current_schema();";
+
+
+const SCHEMA_EXISTS: &'static str = "
+SELECT EXISTS (SELECT 1 FROM pg_catalog.pg_namespace WHERE nspname = $1);";
+
+const LOCK_TIMEOUT_DIRECTIVE: &'static str = "lock-timeout";
+
+const STATEMENT_TIMEOUT_DIRECTIVE: &'static str = "statement-timeout";
+
+const IDLE_TIMEOUT_DIRECTIVE: &'static str = "idle-in-transaction-timeout";
+
+const CURRENT_SETTING: &'static str = "SELECT current_setting($1);";
+
+const SET_LOCAL: &'static str = "SELECT set_config($1, $2, true);";
+
+const SCHEMA_SEQUENCES: &'static str = "
+SELECT c.relname FROM pg_catalog.pg_class c
+JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+WHERE n.nspname = current_schema() AND c.relkind = 'S' AND c.relname <> '_scurry_id_seq'
+  AND NOT EXISTS (SELECT 1 FROM pg_catalog.pg_depend d WHERE d.objid = c.oid AND d.deptype = 'i')
+ORDER BY c.relname;";
+
+const SCHEMA_TABLES: &'static str = "
+SELECT c.relname FROM pg_catalog.pg_class c
+JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+WHERE n.nspname = current_schema() AND c.relkind = 'r' AND c.relname <> '_scurry'
+ORDER BY c.relname;";
+
+const SCHEMA_COLUMNS: &'static str = "
+SELECT c.relname, a.attname, pg_catalog.format_type(a.atttypid, a.atttypmod), a.attnotnull,
+       pg_catalog.pg_get_expr(d.adbin, d.adrelid)
+FROM pg_catalog.pg_attribute a
+JOIN pg_catalog.pg_class c ON c.oid = a.attrelid
+JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+LEFT JOIN pg_catalog.pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum
+WHERE n.nspname = current_schema() AND c.relkind = 'r' AND c.relname <> '_scurry'
+  AND a.attnum > 0 AND NOT a.attisdropped
+ORDER BY c.relname, a.attnum;";
+
+const SCHEMA_CONSTRAINTS: &'static str = "
+SELECT c.relname, con.conname, pg_catalog.pg_get_constraintdef(con.oid, true)
+FROM pg_catalog.pg_constraint con
+JOIN pg_catalog.pg_class c ON c.oid = con.conrelid
+JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+WHERE n.nspname = current_schema() AND c.relkind = 'r' AND c.relname <> '_scurry'
+ORDER BY c.relname, con.conname;";
+
+const SCHEMA_INDEXES: &'static str = "
+SELECT c.relname, i.relname, pg_catalog.pg_get_indexdef(i.oid)
+FROM pg_catalog.pg_index x
+JOIN pg_catalog.pg_class c ON c.oid = x.indrelid
+JOIN pg_catalog.pg_class i ON i.oid = x.indexrelid
+JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+WHERE n.nspname = current_schema() AND c.relkind = 'r' AND c.relname <> '_scurry'
+  AND NOT EXISTS (SELECT 1 FROM pg_catalog.pg_constraint con WHERE con.conindid = i.oid)
+ORDER BY c.relname, i.relname;";
+
+const SCHEMA_VIEWS: &'static str = "
+SELECT c.relname, pg_catalog.pg_get_viewdef(c.oid, true)
+FROM pg_catalog.pg_class c
+JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+WHERE n.nspname = current_schema() AND c.relkind = 'v'
+ORDER BY c.relname;";
+
+/// A migration implemented in Rust.  `up` runs inside the same savepoint a SQL migration would,
+/// within the run's transaction.  Implemented for any matching closure.
+pub trait PostgresMigration {
+    fn up(&self, xact: &Transaction) -> Result<(), ScurryError>;
+}
+
+impl<F> PostgresMigration for F
+    where F: Fn(&Transaction) -> Result<(), ScurryError>
+{
+    fn up(&self, xact: &Transaction) -> Result<(), ScurryError> {
+        self(xact)
+    }
+}
+
+type CodeMigrations = BTreeMap<String, (Version, Box<PostgresMigration>)>;
 
 pub struct Postgres {
     conn: Connection,
-    migrations_dir: String,
+    locations: Locations,
+    listeners: Listeners,
+    placeholders: Placeholders,
+    code_migrations: CodeMigrations,
+    schema_dump: Option<String>,
+    seeds_dirs: Vec<String>,
+    environment: Option<String>,
+    schema: Option<String>,
+    fallback_schemas: Vec<String>,
+    scratch_url: Option<String>,
+    timeouts: Timeouts,
+    lock_retries: u32,
+    retry_backoff: Duration,
+}
+
+/// Limits on how long `migrate` waits, set with `SET LOCAL` in its transaction.  `None` leaves
+/// the server's setting alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// `lock_timeout`: how long a statement waits for a lock before failing with
+    /// `ScurryError::LockTimeout`, rather than queueing everything behind it.
+    pub lock: Option<Duration>,
+    /// `statement_timeout`: how long any one statement may run.
+    pub statement: Option<Duration>,
+    /// `idle_in_transaction_session_timeout`: how long the transaction may sit idle, e.g. while a
+    /// Rust migration does other work.
+    pub idle_in_transaction: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Reads the `-- scurry:lock-timeout`, `statement-timeout` and `idle-in-transaction-timeout`
+    /// directives of a script, each taking a number with an optional `ms`, `s`, `min` or `h`
+    /// unit (milliseconds if there is none).
+    pub fn from_directives(contents: &str) -> Result<Timeouts, ScurryError> {
+        let directives = Directives::parse(contents);
+        let read = |name: &str| -> Result<Option<Duration>, ScurryError> {
+            match directives.get(name) {
+                Some(value) => {
+                    match parse_timeout(value) {
+                        Some(timeout) => Ok(Some(timeout)),
+                        None => {
+                            Err(ScurryError::Parse(format!("Invalid {} directive '{}'",
+                                                           name,
+                                                           value)))
+                        }
+                    }
+                }
+                None => Ok(None),
+            }
+        };
+        Ok(Timeouts {
+            lock: try!(read(LOCK_TIMEOUT_DIRECTIVE)),
+            statement: try!(read(STATEMENT_TIMEOUT_DIRECTIVE)),
+            idle_in_transaction: try!(read(IDLE_TIMEOUT_DIRECTIVE)),
+        })
+    }
+
+    /// The settings that are set, by their Postgres names, as values `SET` accepts.
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        let settings = [("lock_timeout", self.lock),
+                        ("statement_timeout", self.statement),
+                        ("idle_in_transaction_session_timeout", self.idle_in_transaction)];
+        settings.iter()
+            .filter_map(|&(name, timeout)| timeout.map(|t| (name, format!("{}ms", millis(t)))))
+            .collect()
+    }
+
+    /// Sets the timeouts that are set for the rest of the transaction, leaving the others at
+    /// whatever the server, the session or a callback made them.  Returns the values they had
+    /// before, so they can be put back with `restore`.
+    fn set_local(&self, xact: &Transaction) -> Result<Vec<(&'static str, String)>, ScurryError> {
+        let mut previous = vec![];
+        for (name, value) in self.settings() {
+            for row in &try!(xact.query(CURRENT_SETTING, &[&name])) {
+                previous.push((name, row.get(0)));
+            }
+            try!(xact.execute(SET_LOCAL, &[&name, &value]));
+            debug!("Set {} to {}", name, value);
+        }
+        Ok(previous)
+    }
+}
+
+/// Puts back the settings saved by `Timeouts::set_local`.
+fn restore_settings(xact: &Transaction,
+                    previous: &[(&'static str, String)])
+                    -> Result<(), ScurryError> {
+    for &(name, ref value) in previous {
+        try!(xact.execute(SET_LOCAL, &[&name, value]));
+    }
+    Ok(())
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
 }
+
+/// Parses a timeout in the form the directives take: `500`, `500ms`, `5s`, `2min` or `1h`.
+pub fn parse_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_digit(10)).unwrap_or(value.len());
+    let amount: u64 = match value[..split].parse() {
+        Ok(amount) => amount,
+        Err(_) => return None,
+    };
+    let unit = match value[split..].trim() {
+        "" | "ms" => 1,
+        "s" => 1000,
+        "min" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        _ => return None,
+    };
+    amount.checked_mul(unit).map(Duration::from_millis)
+}
+
 pub fn establish(conn: Connection, migrations_dir: &str) -> Postgres {
     Postgres {
         conn: conn,
-        migrations_dir: migrations_dir.into(),
+        locations: Locations::new(migrations_dir),
+        listeners: Listeners::default(),
+        placeholders: Placeholders::new(),
+        code_migrations: BTreeMap::new(),
+        schema_dump: None,
+        seeds_dirs: vec![],
+        environment: None,
+        schema: None,
+        fallback_schemas: vec![],
+        scratch_url: None,
+        timeouts: Timeouts::default(),
+        lock_retries: 3,
+        retry_backoff: Duration::from_secs(1),
+    }
+}
+
+impl Postgres {
+    /// Registers a listener to be notified as migrations are applied.
+    pub fn with_listener<L>(mut self, listener: L) -> Postgres
+        where L: MigrationListener + 'static
+    {
+        self.listeners.add(Box::new(listener));
+        self
+    }
+
+    /// Adds another directory to read migrations from.
+    pub fn with_migrations_dir(mut self, dir: &str) -> Postgres {
+        self.locations.add(dir);
+        self
+    }
+
+    /// Replaces the directories migrations are read from.
+    pub fn with_locations(mut self, locations: Locations) -> Postgres {
+        self.locations = locations;
+        self
+    }
+
+    /// Whether to look for migrations in subdirectories as well.
+    pub fn with_recursive_scan(mut self, recursive: bool) -> Postgres {
+        self.locations.recursive = recursive;
+        self
+    }
+
+    /// Sets the value substituted for `${name}` in migration and callback scripts.
+    pub fn with_placeholder(mut self, name: &str, value: &str) -> Postgres {
+        self.placeholders.insert(name, value);
+        self
+    }
+
+    /// Replaces the placeholder values and leniency, e.g. with ones read from a file by
+    /// `Placeholders::read_file`.
+    pub fn with_placeholders(mut self, placeholders: Placeholders) -> Postgres {
+        self.placeholders = placeholders;
+        self
+    }
+
+    /// Registers a Rust migration, applied in version order alongside the SQL files.  The
+    /// `checksum` is recorded in place of a file hash; without one, a checksum is derived from
+    /// the version and name, so changes to the code won't be flagged as drift.
+    pub fn with_code_migration<M>(mut self,
+                                  version: &str,
+                                  name: &str,
+                                  checksum: Option<&str>,
+                                  migration: M)
+                                  -> Postgres
+        where M: PostgresMigration + 'static
+    {
+        let v = util::code_version(version, name, checksum);
+        self.code_migrations.insert(version.into(), (v, Box::new(migration)));
+        self
+    }
+
+    /// Writes a dump of the schema to `path` after every successful `migrate`.
+    pub fn with_schema_dump(mut self, path: &str) -> Postgres {
+        self.schema_dump = Some(path.into());
+        self
+    }
+
+    /// Adds a directory to read seed scripts from.
+    pub fn with_seeds_dir(mut self, dir: &str) -> Postgres {
+        self.seeds_dirs.push(dir.into());
+        self
+    }
+
+    /// Selects the environment whose seeds are applied alongside the shared ones.
+    pub fn with_environment(mut self, environment: &str) -> Postgres {
+        self.environment = Some(environment.into());
+        self
+    }
+
+    /// Works in `schema` instead of the connection's default: the search path is set to it
+    /// alone before anything else, so it gets its own metadata table and unqualified names never
+    /// resolve to objects elsewhere.  `migrate` creates the schema if it doesn't exist yet.
+    pub fn with_schema(mut self, schema: &str) -> Postgres {
+        self.schema = Some(schema.into());
+        self
+    }
+
+    /// Puts `schema` on the search path after the one given to `with_schema`, e.g. `public` for
+    /// shared extensions and types.  Names not found in the tenant's schema then resolve there.
+    pub fn with_fallback_schema(mut self, schema: &str) -> Postgres {
+        self.fallback_schemas.push(schema.into());
+        self
+    }
+
+    /// Connects `drift` and `squash` to the database at `url` to replay migrations in.  It must
+    /// be empty and used by nothing else; the replay runs in a transaction that is rolled back,
+    /// so it stays empty.  Without one, both fail rather than touch the database being migrated.
+    pub fn with_scratch_database(mut self, url: &str) -> Postgres {
+        self.scratch_url = Some(url.into());
+        self
+    }
+
+    /// Sets the timeouts `migrate` runs with.  Migrations can override them for themselves with
+    /// header directives, e.g. `-- scurry:lock-timeout 2s`.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Postgres {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// How many times `migrate` starts over after failing with `ScurryError::LockTimeout`, and
+    /// how long it waits before the first retry; the wait doubles each time.  Defaults to 3
+    /// retries after 1 second.
+    pub fn with_lock_retries(mut self, retries: u32, backoff: Duration) -> Postgres {
+        self.lock_retries = retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Puts the schema given to `with_schema`, if any, first on the connection's search path,
+    /// creating it first if `create` is set.  Without one, checks that the metadata table isn't
+    /// in a schema behind the current one, where older versions would have used it.
+    fn enter_schema(&self, create: bool) -> Result<(), ScurryError> {
+        let schema = match self.schema {
+            Some(ref schema) => schema,
+            None => {
+                for row in &try!(self.conn.query(METADATA_ELSEWHERE, &[])) {
+                    return Err(ScurryError::MisplacedMetadata(row.get(0)));
+                }
+                return Ok(());
+            }
+        };
+        if create {
+            let create = format!("CREATE SCHEMA IF NOT EXISTS {};", quote_identifier(schema));
+            try!(self.conn.batch_execute(&create));
+        } else {
+            let mut exists = false;
+            for row in &try!(self.conn.query(SCHEMA_EXISTS, &[schema])) {
+                exists = row.get(0);
+            }
+            if !exists {
+                return Err(ScurryError::MissingSchema(schema.clone()));
+            }
+        }
+        let path = Some(schema)
+            .into_iter()
+            .chain(self.fallback_schemas.iter())
+            .map(|s| quote_identifier(s))
+            .collect::<Vec<_>>();
+        try!(self.conn.batch_execute(&format!("SET search_path TO {};", path.join(", "))));
+        Ok(())
+    }
+
+    fn available_versions(&self) -> Result<Vec<Version>, ScurryError> {
+        let files = try!(util::calculate_available_versions(&self.locations,
+                                                      Some(Dialect::Postgres)));
+        let code = self.code_migrations.values().map(|&(ref v, _)| v.clone()).collect();
+        util::merge_versions(files, code)
+    }
+
+    /// Runs `versions` in the database given to `with_scratch_database`, inside a transaction
+    /// that is always rolled back, and returns the schema they produced.
+    fn scratch_schema(&self, versions: &[&Version]) -> Result<Schema, ScurryError> {
+        let url = match self.scratch_url {
+            Some(ref url) => url,
+            None => return Err(ScurryError::MissingScratchDatabase),
+        };
+        let database = try!(current_database(&self.conn));
+        let placeholders = self.placeholders.with_builtins(METADATA_TABLE, &database);
+        let callbacks = try!(try!(util::find_callbacks(&self.locations, Dialect::Postgres))
+            .substitute(&placeholders));
+        let scripts = try!(util::load_scripts(versions, &placeholders));
+        let scratch = try!(Connection::connect(url.as_str(), TlsMode::None)
+            .map_err(|e| ScurryError::Connection(Box::new(e))));
+        let xact = try!(scratch.transaction());
+        info!("Building scratch schema from {} migrations", versions.len());
+        try!(self.enter_scratch_schema(&xact));
+        try!(create_metadata_table(&xact));
+        try!(run_callbacks(&xact, &callbacks, Callback::BeforeMigrate));
+        for (v, contents) in versions.iter().zip(scripts.iter()) {
+            try!(apply_migration(&xact, v, contents, &callbacks, &self.code_migrations)
+                .map_err(|e| util::migration_failed(v, e)));
+        }
+        try!(run_callbacks(&xact, &callbacks, Callback::AfterMigrate));
+        let schema = try!(introspect(&xact));
+        xact.set_rollback();
+        try!(xact.finish());
+        Ok(schema)
+    }
+
+    /// Creates the schemas given to `with_schema` and `with_fallback_schema` in the scratch
+    /// database and puts them on its search path for the rest of `xact`, mirroring
+    /// `enter_schema`.
+    fn enter_scratch_schema(&self, xact: &Transaction) -> Result<(), ScurryError> {
+        let schema = match self.schema {
+            Some(ref schema) => schema,
+            None => return Ok(()),
+        };
+        let path = Some(schema)
+            .into_iter()
+            .chain(self.fallback_schemas.iter())
+            .map(|s| quote_identifier(s))
+            .collect::<Vec<_>>();
+        for s in &path {
+            try!(xact.batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {};", s)));
+        }
+        try!(xact.batch_execute(&format!("SET LOCAL search_path TO {};", path.join(", "))));
+        Ok(())
     }
 }
 
-fn history_table_exists(xact: &Transaction) -> Result<bool, ScurryError> {
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn history_table_exists<C: GenericConnection>(xact: &C) -> Result<bool, ScurryError> {
     let exists = try!(xact.query(METADATA_EXISTS, &[]));
     for row in &exists {
         let res: bool = row.get(0);
@@ -53,9 +519,73 @@ fn history_table_exists(xact: &Transaction) -> Result<bool, ScurryError> {
     Ok(false)
 }
 
-fn write_history_line(xact: &Transaction, version: &Version) -> Result<(), ScurryError> {
+fn current_database<C: GenericConnection>(xact: &C) -> Result<String, ScurryError> {
+    let rows = try!(xact.query(CURRENT_DATABASE, &[]));
+    for row in &rows {
+        return Ok(row.get(0));
+    }
+    Ok(String::new())
+}
+
+/// Reads the sequences, tables and views of the current schema.  Index definitions have the
+/// schema name stripped so dumps of identical schemas under different names compare equal.
+fn introspect<C: GenericConnection>(conn: &C) -> Result<Schema, ScurryError> {
+    let mut current = String::new();
+    for row in &try!(conn.query(CURRENT_SCHEMA, &[])) {
+        current = row.get(0);
+    }
+    let qualifier = format!(" ON {}.", current);
+    let mut result = Schema::default();
+    for row in &try!(conn.query(SCHEMA_SEQUENCES, &[])) {
+        result.sequences.push(row.get(0));
+    }
+    for row in &try!(conn.query(SCHEMA_TABLES, &[])) {
+        let name: String = row.get(0);
+        result.table_mut(&name);
+    }
+    for row in &try!(conn.query(SCHEMA_COLUMNS, &[])) {
+        let table: String = row.get(0);
+        result.table_mut(&table).columns.push(Column {
+            name: row.get(1),
+            data_type: row.get(2),
+            not_null: row.get(3),
+            default: row.get(4),
+        });
+    }
+    for row in &try!(conn.query(SCHEMA_CONSTRAINTS, &[])) {
+        let table: String = row.get(0);
+        result.table_mut(&table).constraints.push(Constraint {
+            name: row.get(1),
+            definition: row.get(2),
+        });
+    }
+    for row in &try!(conn.query(SCHEMA_INDEXES, &[])) {
+        let table: String = row.get(0);
+        let definition: String = row.get(2);
+        result.table_mut(&table).indexes.push(Index {
+            name: row.get(1),
+            definition: definition.replace(&qualifier, " ON "),
+        });
+    }
+    for row in &try!(conn.query(SCHEMA_VIEWS, &[])) {
+        let name: String = row.get(0);
+        let body: String = row.get(1);
+        result.views.push(View {
+            definition: format!("CREATE VIEW {} AS\n{}", name, schema::trim_definition(&body)),
+            name: name,
+        });
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// Creates the scratch schema and puts it first on the search path for the rest of `xact`.
+fn write_history_line(xact: &Transaction,
+                      version: &Version,
+                      status: MigrationStatus)
+                      -> Result<(), ScurryError> {
     try!(xact.execute(INSERT_HISTORY_LINE,
-                      &[&version.hash, &version.name, &version.version]));
+                      &[&version.hash, &version.name, &version.version, &status.name()]));
     Ok(())
 }
 
@@ -70,37 +600,166 @@ fn create_metadata_table(xact: &Transaction) -> Result<(), ScurryError> {
     if !exists {
         try!(xact.batch_execute(CREATE_METADATA_TABLE));
         info!("Metadata table created");
+    } else {
+        try!(upgrade_metadata_table(xact));
     }
     Ok(())
 }
 
-fn get_history(xact: &Transaction) -> Result<Vec<ScurryMetadata>, ScurryError> {
+/// Adds any columns a metadata table created by an older version is missing.
+fn upgrade_metadata_table<C: GenericConnection>(conn: &C) -> Result<(), ScurryError> {
+    for &(column, definition) in METADATA_COLUMNS {
+        let mut exists = false;
+        for row in &try!(conn.query(METADATA_COLUMN_EXISTS, &[&column])) {
+            exists = row.get(0);
+        }
+        if !exists {
+            try!(conn.batch_execute(&format!("ALTER TABLE _scurry ADD COLUMN {} {};",
+                                             column,
+                                             definition)));
+            info!("Added column {} to metadata table", column);
+        }
+    }
+    Ok(())
+}
+
+/// Runs the seeds for `environment` that are new or have changed since they were last applied,
+/// each in its own savepoint.  Returns how many ran.
+fn apply_seeds(xact: &Transaction,
+               seeds_dirs: &[String],
+               environment: Option<&str>,
+               placeholders: &Placeholders)
+               -> Result<usize, ScurryError> {
+    let seeds = try!(seeds::find_seeds(seeds_dirs, environment, Dialect::Postgres));
+    let mut installed: Vec<(String, String)> = vec![];
+    for row in &try!(xact.query(GET_SEEDS, &[])) {
+        installed.push((row.get(0), row.get(1)));
+    }
+    let pending = seeds::pending_seeds(&seeds, &installed);
+    let scripts = try!(util::load_scripts(&pending, placeholders));
+    for (seed, contents) in pending.iter().zip(scripts.iter()) {
+        info!("Applying seed {}...", &seed.version);
+        let sub_xact = try!(xact.transaction());
+        try!(execute_script(&sub_xact,
+                            contents,
+                            |s, e| util::statement_failed(seed, s, e))
+            .map_err(|e| util::migration_failed(seed, e)));
+        try!(sub_xact.execute(DELETE_SEED, &[&seed.version]));
+        try!(sub_xact.execute(INSERT_SEED, &[&seed.hash, &seed.name, &seed.version]));
+        try!(sub_xact.commit());
+    }
+    Ok(pending.len())
+}
+
+fn get_history<C: GenericConnection>(xact: &C) -> Result<Vec<ScurryMetadata>, ScurryError> {
     let revisions_query = try!(xact.query(GET_ALL_REVISIONS, &[]));
     Ok(revisions_query.iter().map(ScurryMetadata::from).collect::<Vec<_>>())
 }
 
+/// The installed history, or nothing if the metadata table hasn't been created yet.  An older
+/// table is brought up to date in `conn`, so read-only callers pass a transaction they roll back.
+fn installed_history<C>(conn: &C) -> Result<Vec<ScurryMetadata>, ScurryError>
+    where C: GenericConnection
+{
+    if try!(history_table_exists(conn)) {
+        try!(upgrade_metadata_table(conn));
+        get_history(conn)
+    } else {
+        Ok(vec![])
+    }
+}
+
 fn clear_history_table(xact: &Transaction) -> Result<(), ScurryError> {
     try!(xact.execute(DELETE_HISTORY, &[]));
     Ok(())
 }
 
-fn apply_migration(xact: &Transaction, version: &Version) -> Result<(), ScurryError> {
-    let sub_xact = try!(xact.transaction());
-    let contents = try!(util::get_file_contents(&version.path));
-    try!(sub_xact.batch_execute(&contents));
-    try!(write_history_line(&sub_xact, &version));
-    try!(sub_xact.commit());
+fn execute_script<F>(xact: &Transaction, contents: &str, on_error: F) -> Result<(), ScurryError>
+    where F: Fn(&Statement, ScurryError) -> ScurryError
+{
+    for statement in sql::split_statements(contents, Dialect::Postgres) {
+        if let Err(e) = xact.batch_execute(&statement.text) {
+            return Err(on_error(&statement, e.into()));
+        }
+    }
     Ok(())
 }
 
-impl ScurryConnection for Postgres {
-    type DbConnection = Connection;
+fn run_callbacks(xact: &Transaction,
+                 callbacks: &Callbacks,
+                 callback: Callback)
+                 -> Result<(), ScurryError> {
+    for (path, contents) in callbacks.scripts(callback) {
+        debug!("Running {} callback {}", callback, path);
+        try!(execute_script(xact,
+                            contents,
+                            |s, e| util::callback_failed(callback, path, s, e)));
+    }
+    Ok(())
+}
 
-    fn migrate(&mut self, desired_version: DesiredVersion) -> Result<usize, ScurryError> {
-        let versions = try!(util::calculate_available_versions(&self.migrations_dir));
+/// Evaluates a migration's guard query.  No rows or a `NULL` count as false.
+fn guard_holds(xact: &Transaction, query: &str) -> Result<bool, ScurryError> {
+    for row in &try!(xact.query(query, &[])) {
+        return match row.get_opt::<_, Option<bool>>(0) {
+            Some(Ok(holds)) => Ok(holds.unwrap_or(false)),
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(false),
+        };
+    }
+    Ok(false)
+}
+
+fn apply_migration(xact: &Transaction,
+                   version: &Version,
+                   contents: &str,
+                   callbacks: &Callbacks,
+                   code: &CodeMigrations)
+                   -> Result<MigrationStatus, ScurryError> {
+    let sub_xact = try!(xact.transaction());
+    if let Some(query) = util::guard(contents) {
+        if !try!(guard_holds(&sub_xact, &query)) {
+            info!("Skipped version {}, its guard was false", &version.version);
+            try!(write_history_line(&sub_xact, &version, MigrationStatus::Skipped));
+            try!(sub_xact.commit());
+            return Ok(MigrationStatus::Skipped);
+        }
+    }
+    try!(run_callbacks(&sub_xact, callbacks, Callback::BeforeEachMigrate));
+    match version.kind {
+        MigrationKind::Sql => {
+            try!(execute_script(&sub_xact,
+                                contents,
+                                |s, e| util::statement_failed(version, s, e)));
+        }
+        MigrationKind::Rust => {
+            match code.get(&version.version) {
+                Some(&(_, ref migration)) => try!(migration.up(&sub_xact)),
+                None => return Err(ScurryError::MissingCodeMigration(version.version.clone())),
+            }
+        }
+    }
+    try!(run_callbacks(&sub_xact, callbacks, Callback::AfterEachMigrate));
+    try!(write_history_line(&sub_xact, &version, MigrationStatus::Applied));
+    try!(sub_xact.commit());
+    Ok(MigrationStatus::Applied)
+}
+
+impl Postgres {
+    /// A single attempt at `migrate`, all in one transaction.
+    fn migrate_once(&mut self, desired_version: DesiredVersion) -> Result<usize, ScurryError> {
+        let started = Instant::now();
+        try!(self.enter_schema(true));
+        let versions = try!(self.available_versions());
         info!("Found {} migrations", versions.len());
         let xact = try!(self.conn.transaction());
+        try!(self.timeouts.set_local(&xact));
+        let database = try!(current_database(&xact));
+        let placeholders = self.placeholders.with_builtins(METADATA_TABLE, &database);
+        let callbacks = try!(try!(util::find_callbacks(&self.locations, Dialect::Postgres))
+            .substitute(&placeholders));
         try!(create_metadata_table(&xact));
+        try!(run_callbacks(&xact, &callbacks, Callback::BeforeMigrate));
         try!(lock_table(&xact));
         let history = try!(get_history(&xact));
         try!(util::verify_common_history(&versions, &history));
@@ -114,40 +773,132 @@ impl ScurryConnection for Postgres {
             }
         }
 
-        let upgrade_path = util::choose_upgrade_path(&versions, &latest_version, &desired_version);
+        let upgrade_path = util::choose_upgrade_path(&versions, &history, &desired_version);
         let upgrade_len = upgrade_path.len();
+        let scripts = try!(util::load_scripts(&upgrade_path, &placeholders));
+        let script_timeouts = try!(scripts.iter()
+            .map(|s| Timeouts::from_directives(s))
+            .collect::<Result<Vec<_>, ScurryError>>());
+        try!(self.listeners.before_migrate(&upgrade_path));
         info!("Applying {} migrations", upgrade_len);
-        for v in upgrade_path {
+        let steps = upgrade_path.iter().zip(scripts.iter()).zip(script_timeouts.iter());
+        for ((v, contents), timeouts) in steps {
             info!("Applying version {}...", &v.version);
-            try!(apply_migration(&xact, &v));
+            try!(self.listeners.before_each(v));
+            let applied_at = Instant::now();
+            let previous = try!(timeouts.set_local(&xact));
+            if let Err(e) = apply_migration(&xact,
+                                            v,
+                                            contents,
+                                            &callbacks,
+                                            &self.code_migrations)
+                .map_err(|e| util::migration_failed(v, e)) {
+                self.listeners.on_error(v, &e);
+                return Err(e);
+            }
+            try!(restore_settings(&xact, &previous));
+            self.listeners.after_each(v, applied_at.elapsed());
         }
+        let seeded = if let DesiredVersion::Latest = desired_version {
+            try!(apply_seeds(&xact,
+                             &self.seeds_dirs,
+                             self.environment.as_ref().map(|e| e.as_str()),
+                             &placeholders))
+        } else {
+            0
+        };
+        try!(run_callbacks(&xact, &callbacks, Callback::AfterMigrate));
         try!(xact.commit());
-        Ok(upgrade_len)
+        if let Some(ref path) = self.schema_dump {
+            try!(try!(self.schema()).write_to(path));
+        }
+        let summary = util::summarize(&latest_version, &upgrade_path, started.elapsed());
+        self.listeners.after_migrate(&summary);
+        Ok(upgrade_len + seeded)
+    }
+}
+
+impl ScurryConnection for Postgres {
+    type DbConnection = Connection;
+
+    fn migrate(&mut self, desired_version: DesiredVersion) -> Result<usize, ScurryError> {
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            match self.migrate_once(desired_version.clone()) {
+                Err(ref e) if e.is_lock_timeout() && attempt < self.lock_retries => {
+                    attempt += 1;
+                    warn!("{}; retrying in {:?} ({} of {})",
+                          e,
+                          backoff,
+                          attempt,
+                          self.lock_retries);
+                    thread::sleep(backoff);
+                    backoff = backoff * 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn lint(&self, desired_version: DesiredVersion) -> Result<Vec<LintFinding>, ScurryError> {
+        try!(self.enter_schema(false));
+        let versions = try!(self.available_versions());
+        let history = try!(installed_history(&try!(self.conn.transaction())));
+        let upgrade_path = util::choose_upgrade_path(&versions, &history, &desired_version);
+        lint::lint_versions(&upgrade_path, Dialect::Postgres)
+    }
+
+    fn schema(&self) -> Result<Schema, ScurryError> {
+        try!(self.enter_schema(false));
+        introspect(&self.conn)
+    }
+
+    fn drift(&self) -> Result<Vec<SchemaDifference>, ScurryError> {
+        try!(self.enter_schema(false));
+        let versions = try!(self.available_versions());
+        let actual = try!(introspect(&self.conn));
+        let history = try!(installed_history(&try!(self.conn.transaction())));
+        try!(util::verify_common_history(&versions, &history));
+        let applied = util::applied_versions(&versions, &history);
+        let expected = try!(self.scratch_schema(&applied));
+        Ok(schema::diff(&expected, &actual))
+    }
+
+    fn squash(&self, desired_version: DesiredVersion) -> Result<Baseline, ScurryError> {
+        let versions = try!(self.available_versions());
+        let squashed = util::choose_upgrade_path(&versions, &[], &desired_version);
+        let schema = try!(self.scratch_schema(&squashed));
+        Baseline::new(&squashed, schema)
     }
 
     fn get_differences(&self) -> Result<Vec<HistoryDifferences>, ScurryError> {
-        let available = try!(util::calculate_available_versions(&self.migrations_dir));
+        let available = try!(self.available_versions());
         let installed = try!(self.get_history());
         Ok(util::get_history_differences(&available, &installed))
     }
 
     fn set_schema_level(&self, desired_version: DesiredVersion) -> Result<(), ScurryError> {
-        let versions = try!(util::calculate_available_versions(&self.migrations_dir));
-        let upgrade_path = util::choose_upgrade_path(&versions, &None, &desired_version);
+        let versions = try!(self.available_versions());
+        let upgrade_path = util::choose_upgrade_path(&versions, &[], &desired_version);
         try!(self.override_versions(&upgrade_path));
         Ok(())
     }
 
     fn get_history(&self) -> Result<Vec<ScurryMetadata>, ScurryError> {
-        let revisions_query = try!(self.conn.query(GET_ALL_REVISIONS, &[]));
-        Ok(revisions_query.iter().map(ScurryMetadata::from).collect::<Vec<_>>())
+        try!(self.enter_schema(false));
+        if try!(history_table_exists(&self.conn)) {
+            try!(upgrade_metadata_table(&self.conn));
+        }
+        get_history(&self.conn)
     }
 
     fn override_versions(&self, versions: &[&Version]) -> Result<(), ScurryError> {
+        try!(self.enter_schema(false));
         let xact = try!(self.conn.transaction());
         try!(clear_history_table(&xact));
         for v in versions {
-            try!(write_history_line(&xact, v));
+            try!(write_history_line(&xact, v, MigrationStatus::Applied));
         }
         try!(xact.commit());
         Ok(())
@@ -158,6 +909,31 @@ impl ScurryConnection for Postgres {
     }
 }
 
+/// Runs `query` and returns the first column of each row, e.g. to list tenant schemas with
+/// `SELECT nspname FROM pg_namespace WHERE nspname LIKE 'tenant_%'`.
+pub fn query_schemas(conn: &Connection, query: &str) -> Result<Vec<String>, ScurryError> {
+    let rows = try!(conn.query(query, &[]));
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Migrates each of `schemas` to `desired_version`, as configured by `open`, which is called
+/// once per schema to provide a fresh connection.  Schemas are migrated on up to
+/// `options.parallelism` threads, each in its own transaction.
+pub fn migrate_schemas<F>(schemas: Vec<String>,
+                          desired_version: DesiredVersion,
+                          options: &BatchOptions,
+                          open: F)
+                          -> BatchReport
+    where F: Fn() -> Result<Postgres, ScurryError> + Send + Sync + 'static
+{
+    batch::run(schemas, options, move |schema| {
+        let mut conn = try!(open()).with_schema(schema);
+        let applied = try!(conn.migrate(desired_version.clone()));
+        let history = try!(conn.get_history());
+        Ok((applied, history.last().map(|h| h.script_version.clone())))
+    })
+}
+
 impl<'a> From<Row<'a>> for ScurryMetadata {
     fn from(row: Row<'a>) -> ScurryMetadata {
         ScurryMetadata {
@@ -166,12 +942,21 @@ impl<'a> From<Row<'a>> for ScurryMetadata {
             script_hash: row.get(2),
             script_name: row.get(3),
             script_version: row.get(4),
+            status: MigrationStatus::from_name(&row.get::<_, String>(5)),
         }
     }
 }
 
 impl From<PgError> for ScurryError {
     fn from(e: PgError) -> ScurryError {
-        ScurryError::Sql(Box::new(e))
+        let lock_timeout = match e {
+            PgError::Db(ref db) => db.code == SqlState::LockNotAvailable,
+            _ => false,
+        };
+        if lock_timeout {
+            ScurryError::LockTimeout(Box::new(e))
+        } else {
+            ScurryError::Sql(Box::new(e))
+        }
     }
 }
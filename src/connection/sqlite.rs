@@ -1,10 +1,30 @@
-use rusqlite::{Connection, Error as SqliteError};
+use rusqlite::{Connection, Error as SqliteError, Row, TransactionBehavior};
+use rusqlite::ffi::ErrorCode;
 use error::ScurryError;
-use models::ScurryMetadata;
-use versions::{Version, DesiredVersion};
+use models::{ScurryMetadata, MigrationStatus};
+use versions::{Version, DesiredVersion, MigrationKind};
+use std::collections::BTreeMap;
 use chrono::UTC;
 use connection::ScurryConnection;
-use util::{self, HistoryDifferences};
+use util::{self, HistoryDifferences, Locations};
+use listener::{MigrationListener, Listeners};
+use placeholders::Placeholders;
+use lint::{self, LintFinding};
+use squash::Baseline;
+use seeds;
+use schema::{self, Schema, SchemaDifference, Table, Column, Constraint, Index, View};
+use std::time::{Duration, Instant};
+use std::fs;
+use std::path::Path;
+use batch::{self, BatchOptions, BatchReport};
+use backup::{self, BackupSettings};
+use sql::{self, Dialect, Statement};
+use callbacks::{Callback, Callbacks};
+use directives::Directives;
+
+const METADATA_TABLE: &'static str = "_scurry";
+
+const DATABASE_LIST: &'static str = "PRAGMA database_list;";
 
 const METADATA_EXISTS: &'static str = "
 SELECT name FROM sqlite_master WHERE type = 'table' and name = '_scurry';";
@@ -15,24 +35,267 @@ CREATE TABLE _scurry (
     migration_date TEXT NOT NULL,
     script_hash TEXT NOT NULL,
     script_name TEXT NOT NULL,
-    script_version TEXT NOT NULL
+    script_version TEXT NOT NULL,
+    script_type TEXT NOT NULL DEFAULT 'versioned',
+    status TEXT NOT NULL DEFAULT 'applied'
 );";
 
-const INSERT_HISTORY_LINE: &'static str = "INSERT INTO _scurry(script_hash, script_name, script_version, migration_date) values($1, $2, $3, $4);";
+/// Columns added to the metadata table since it was first released, with their definitions.
+/// Tables created by older versions get them on first use.
+const METADATA_COLUMNS: &'static [(&'static str, &'static str)] =
+    &[("script_type", "TEXT NOT NULL DEFAULT 'versioned'"),
+      ("status", "TEXT NOT NULL DEFAULT 'applied'")];
+
+const INSERT_HISTORY_LINE: &'static str = "INSERT INTO _scurry(script_hash, script_name, script_version, migration_date, status) values($1, $2, $3, $4, $5);";
+
+const GET_ALL_REVISIONS: &'static str = "SELECT id, migration_date, script_hash, script_name, script_version, status FROM _scurry WHERE script_type = 'versioned' ORDER BY script_version ASC;";
+
+const GET_SEEDS: &'static str = "SELECT script_version, script_hash FROM _scurry \
+                                 WHERE script_type = 'seed';";
+
+const DELETE_SEED: &'static str = "DELETE FROM _scurry WHERE script_type = 'seed' \
+                                   AND script_version = $1;";
+
+const INSERT_SEED: &'static str = "INSERT INTO _scurry(script_hash, script_name, script_version, \
+                                   migration_date, script_type) values($1, $2, $3, $4, 'seed');";
 
-const GET_ALL_REVISIONS: &'static str = "SELECT id, migration_date, script_hash, script_name, script_version FROM _scurry ORDER BY script_version ASC;";
+const DELETE_HISTORY: &'static str = "DELETE FROM _scurry WHERE script_type = 'versioned';";
 
-const DELETE_HISTORY: &'static str = "DELETE FROM _scurry;";
+const PRAGMA_DIRECTIVE: &'static str = "pragma";
+
+const FOREIGN_KEY_CHECK: &'static str = "PRAGMA foreign_key_check;";
+
+const VERSION_INSTALLED: &'static str = "SELECT 1 FROM _scurry WHERE script_type = 'versioned' \
+                                         AND script_version = $1;";
+
+/// Extensions recognised as SQLite databases when a directory is given to `find_databases`.
+const DATABASE_EXTENSIONS: [&'static str; 3] = ["db", "sqlite", "sqlite3"];
+
+const SCHEMA_OBJECTS: &'static str = "
+SELECT type, name, tbl_name, sql FROM sqlite_master
+WHERE type IN ('table', 'index', 'view')
+  AND name NOT LIKE 'sqlite_%' AND tbl_name <> '_scurry'
+ORDER BY type, name;";
+
+/// A migration implemented in Rust.  `up` is handed the connection while the migration's
+/// transaction is open, exactly as a SQL migration would run.  Implemented for any matching
+/// closure.
+pub trait SqliteMigration {
+    fn up(&self, xact: &Connection) -> Result<(), ScurryError>;
+}
+
+impl<F> SqliteMigration for F
+    where F: Fn(&Connection) -> Result<(), ScurryError>
+{
+    fn up(&self, xact: &Connection) -> Result<(), ScurryError> {
+        self(xact)
+    }
+}
+
+type CodeMigrations = BTreeMap<String, (Version, Box<SqliteMigration>)>;
 
 pub struct Sqlite {
     conn: Connection,
-    migrations_dir: String,
+    locations: Locations,
+    listeners: Listeners,
+    placeholders: Placeholders,
+    code_migrations: CodeMigrations,
+    schema_dump: Option<String>,
+    seeds_dirs: Vec<String>,
+    environment: Option<String>,
+    /// How many backups to keep, if `migrate` should take them.
+    backups: Option<usize>,
+    backup_dir: Option<String>,
+    atomicity: Atomicity,
+    busy_timeout: Duration,
+}
+
+/// How much of a `migrate` run is undone when a migration fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Atomicity {
+    /// Everything, as on Postgres: the whole run is one transaction, holding the write lock
+    /// throughout.
+    WholeRun,
+    /// Only the failing migration.  Each migration is committed on its own, and other
+    /// connections can get in between them.
+    PerMigration,
 }
 
 pub fn establish(conn: Connection, migrations_dir: &str) -> Sqlite {
     Sqlite {
         conn: conn,
-        migrations_dir: migrations_dir.into(),
+        locations: Locations::new(migrations_dir),
+        listeners: Listeners::default(),
+        placeholders: Placeholders::new(),
+        code_migrations: BTreeMap::new(),
+        schema_dump: None,
+        seeds_dirs: vec![],
+        environment: None,
+        backups: None,
+        backup_dir: None,
+        atomicity: Atomicity::WholeRun,
+        busy_timeout: Duration::from_secs(5),
+    }
+}
+
+impl Sqlite {
+    /// Registers a listener to be notified as migrations are applied.
+    pub fn with_listener<L>(mut self, listener: L) -> Sqlite
+        where L: MigrationListener + 'static
+    {
+        self.listeners.add(Box::new(listener));
+        self
+    }
+
+    /// Adds another directory to read migrations from.
+    pub fn with_migrations_dir(mut self, dir: &str) -> Sqlite {
+        self.locations.add(dir);
+        self
+    }
+
+    /// Replaces the directories migrations are read from.
+    pub fn with_locations(mut self, locations: Locations) -> Sqlite {
+        self.locations = locations;
+        self
+    }
+
+    /// Whether to look for migrations in subdirectories as well.
+    pub fn with_recursive_scan(mut self, recursive: bool) -> Sqlite {
+        self.locations.recursive = recursive;
+        self
+    }
+
+    /// Sets the value substituted for `${name}` in migration and callback scripts.
+    pub fn with_placeholder(mut self, name: &str, value: &str) -> Sqlite {
+        self.placeholders.insert(name, value);
+        self
+    }
+
+    /// Replaces the placeholder values and leniency, e.g. with ones read from a file by
+    /// `Placeholders::read_file`.
+    pub fn with_placeholders(mut self, placeholders: Placeholders) -> Sqlite {
+        self.placeholders = placeholders;
+        self
+    }
+
+    /// Registers a Rust migration, applied in version order alongside the SQL files.  The
+    /// `checksum` is recorded in place of a file hash; without one, a checksum is derived from
+    /// the version and name, so changes to the code won't be flagged as drift.
+    pub fn with_code_migration<M>(mut self,
+                                  version: &str,
+                                  name: &str,
+                                  checksum: Option<&str>,
+                                  migration: M)
+                                  -> Sqlite
+        where M: SqliteMigration + 'static
+    {
+        let v = util::code_version(version, name, checksum);
+        self.code_migrations.insert(version.into(), (v, Box::new(migration)));
+        self
+    }
+
+    /// Writes a dump of the schema to `path` after every successful `migrate`.
+    pub fn with_schema_dump(mut self, path: &str) -> Sqlite {
+        self.schema_dump = Some(path.into());
+        self
+    }
+
+    /// Adds a directory to read seed scripts from.
+    pub fn with_seeds_dir(mut self, dir: &str) -> Sqlite {
+        self.seeds_dirs.push(dir.into());
+        self
+    }
+
+    /// Selects the environment whose seeds are applied alongside the shared ones.
+    pub fn with_environment(mut self, environment: &str) -> Sqlite {
+        self.environment = Some(environment.into());
+        self
+    }
+
+    /// Backs the database up before every `migrate` that has migrations to apply, keeping the
+    /// `keep` most recent backups (all of them if `0`).  In-memory databases aren't backed up.
+    pub fn with_backups(mut self, keep: usize) -> Sqlite {
+        self.backups = Some(keep);
+        self
+    }
+
+    /// Whether a failed `migrate` rolls back the whole run (the default) or just the failing
+    /// migration.
+    pub fn with_atomicity(mut self, atomicity: Atomicity) -> Sqlite {
+        self.atomicity = atomicity;
+        self
+    }
+
+    /// How long `migrate` waits for another connection to release its lock on the database
+    /// before failing with `ScurryError::LockTimeout`.  Defaults to 5 seconds.  Set on the
+    /// connection when `migrate` starts.
+    pub fn with_busy_timeout(mut self, timeout: Duration) -> Sqlite {
+        self.busy_timeout = timeout;
+        self
+    }
+
+    /// Keeps backups in `dir` instead of next to the database.
+    pub fn with_backup_dir(mut self, dir: &str) -> Sqlite {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    fn backup_settings(&self) -> BackupSettings {
+        BackupSettings {
+            dir: self.backup_dir.clone(),
+            keep: self.backups.unwrap_or(0),
+        }
+    }
+
+    /// The backups of this database, oldest first.
+    pub fn backups(&self) -> Result<Vec<String>, ScurryError> {
+        let database = try!(database_file(&self.conn));
+        backup::list(&database, &self.backup_settings())
+    }
+
+    /// Replaces the database with `backup`, or with the most recent backup if `None`.  Returns
+    /// the path of the backup restored.
+    pub fn restore(&mut self, backup: Option<&str>) -> Result<String, ScurryError> {
+        let path = match backup {
+            Some(path) => path.to_string(),
+            None => {
+                match try!(self.backups()).pop() {
+                    Some(latest) => latest,
+                    None => {
+                        return Err(ScurryError::NoBackups(try!(database_file(&self.conn))))
+                    }
+                }
+            }
+        };
+        try!(backup::restore(&mut self.conn, &path));
+        Ok(path)
+    }
+
+    fn available_versions(&self) -> Result<Vec<Version>, ScurryError> {
+        let files = try!(util::calculate_available_versions(&self.locations,
+                                                      Some(Dialect::Sqlite)));
+        let code = self.code_migrations.values().map(|&(ref v, _)| v.clone()).collect();
+        util::merge_versions(files, code)
+    }
+
+    /// Runs `versions` against an in-memory database and returns the schema they produced.
+    fn scratch_schema(&self, versions: &[&Version]) -> Result<Schema, ScurryError> {
+        let database = try!(database_file(&self.conn));
+        let placeholders = self.placeholders.with_builtins(METADATA_TABLE, &database);
+        let callbacks = try!(try!(util::find_callbacks(&self.locations, Dialect::Sqlite))
+            .substitute(&placeholders));
+        let scripts = try!(util::load_scripts(versions, &placeholders));
+        info!("Building scratch schema from {} migrations", versions.len());
+        let mut scratch = try!(Connection::open_in_memory());
+        let xact = try!(scratch.transaction());
+        try!(create_metadata_table(&xact));
+        try!(run_callbacks(&xact, &callbacks, Callback::BeforeMigrate));
+        for (v, contents) in versions.iter().zip(scripts.iter()) {
+            try!(apply_migration(&xact, v, contents, &callbacks, &self.code_migrations)
+                .map_err(|e| util::migration_failed(v, e)));
+        }
+        try!(run_callbacks(&xact, &callbacks, Callback::AfterMigrate));
+        introspect(&xact)
     }
 }
 
@@ -43,9 +306,168 @@ fn history_table_exists(xact: &Connection) -> Result<bool, ScurryError> {
         Ok(exists.count() > 0)
 }
 
-fn write_history_line(xact: &Connection, version: &Version) -> Result<(), ScurryError> {
+/// Path of the main database file, or `main` for in-memory databases.
+fn database_file(conn: &Connection) -> Result<String, ScurryError> {
+    let mut stmt = try!(conn.prepare(DATABASE_LIST));
+    let databases = try!(stmt.query_map(&[], |row| {
+        let name: String = row.get(1);
+        let file: String = row.get(2);
+        (name, file)
+    }));
+    for database in databases {
+        let (name, file) = try!(database);
+        if name == "main" && !file.is_empty() {
+            return Ok(file);
+        }
+    }
+    Ok("main".into())
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn query_rows<T, F>(conn: &Connection, sql: &str, f: F) -> Result<Vec<T>, ScurryError>
+    where F: FnMut(&Row) -> T
+{
+    let mut stmt = try!(conn.prepare(sql));
+    let rows = try!(stmt.query_map(&[], f));
+    let mut result = vec![];
+    for row in rows {
+        result.push(try!(row));
+    }
+    Ok(result)
+}
+
+/// Fills in the columns and constraints of `table` from its pragmas.  SQLite doesn't record
+/// constraint names or expose `CHECK` constraints, so primary, foreign and unique keys are named
+/// the way Postgres would name them and checks are left out.
+fn introspect_table(conn: &Connection, table: &mut Table) -> Result<(), ScurryError> {
+    let quoted = quote_identifier(&table.name);
+    let info = try!(query_rows(conn, &format!("PRAGMA table_info({});", quoted), |row| {
+        let name: String = row.get(1);
+        let data_type: String = row.get(2);
+        let not_null: i32 = row.get(3);
+        let default: Option<String> = row.get(4);
+        let pk: i32 = row.get(5);
+        (Column {
+            name: name,
+            data_type: data_type,
+            not_null: not_null != 0,
+            default: default,
+        },
+         pk)
+    }));
+    let mut pk: Vec<(i32, String)> = vec![];
+    for (column, key) in info {
+        if key > 0 {
+            pk.push((key, column.name.clone()));
+        }
+        table.columns.push(column);
+    }
+    if !pk.is_empty() {
+        pk.sort();
+        let columns: Vec<String> = pk.into_iter().map(|(_, c)| c).collect();
+        table.constraints.push(Constraint {
+            name: format!("{}_pkey", table.name),
+            definition: format!("PRIMARY KEY ({})", columns.join(", ")),
+        });
+    }
+
+    let fks = try!(query_rows(conn, &format!("PRAGMA foreign_key_list({});", quoted), |row| {
+        let id: i32 = row.get(0);
+        let target: String = row.get(2);
+        let from: String = row.get(3);
+        let to: Option<String> = row.get(4);
+        let on_update: String = row.get(5);
+        let on_delete: String = row.get(6);
+        (id, target, from, to, on_update, on_delete)
+    }));
+    let mut ids: Vec<i32> = fks.iter().map(|fk| fk.0).collect();
+    ids.dedup();
+    for id in ids {
+        let parts: Vec<_> = fks.iter().filter(|fk| fk.0 == id).collect();
+        let from: Vec<&str> = parts.iter().map(|fk| fk.2.as_str()).collect();
+        let to: Vec<&str> = parts.iter()
+            .filter_map(|fk| fk.3.as_ref().map(|t| t.as_str()))
+            .collect();
+        let mut definition = format!("FOREIGN KEY ({}) REFERENCES {}", from.join(", "), parts[0].1);
+        if !to.is_empty() {
+            definition.push_str(&format!("({})", to.join(", ")));
+        }
+        if parts[0].4 != "NO ACTION" {
+            definition.push_str(&format!(" ON UPDATE {}", parts[0].4));
+        }
+        if parts[0].5 != "NO ACTION" {
+            definition.push_str(&format!(" ON DELETE {}", parts[0].5));
+        }
+        table.constraints.push(Constraint {
+            name: format!("{}_{}_fkey", table.name, from.join("_")),
+            definition: definition,
+        });
+    }
+
+    let indexes = try!(query_rows(conn, &format!("PRAGMA index_list({});", quoted), |row| {
+        let name: String = row.get(1);
+        let origin: String = row.get(3);
+        (name, origin)
+    }));
+    for (index, origin) in indexes {
+        if origin != "u" {
+            continue;
+        }
+        let sql = format!("PRAGMA index_info({});", quote_identifier(&index));
+        let columns = try!(query_rows(conn, &sql, |row| {
+            let name: String = row.get(2);
+            name
+        }));
+        table.constraints.push(Constraint {
+            name: format!("{}_{}_key", table.name, columns.join("_")),
+            definition: format!("UNIQUE ({})", columns.join(", ")),
+        });
+    }
+    Ok(())
+}
+
+/// Reads the tables, indexes and views of the main database.
+fn introspect(conn: &Connection) -> Result<Schema, ScurryError> {
+    let objects = try!(query_rows(conn, SCHEMA_OBJECTS, |row| {
+        let kind: String = row.get(0);
+        let name: String = row.get(1);
+        let table: String = row.get(2);
+        let sql: Option<String> = row.get(3);
+        (kind, name, table, sql)
+    }));
+    let mut result = Schema::default();
+    for (kind, name, table, sql) in objects {
+        match (kind.as_str(), sql) {
+            ("table", _) => try!(introspect_table(conn, result.table_mut(&name))),
+            ("index", Some(sql)) => {
+                result.table_mut(&table).indexes.push(Index {
+                    name: name,
+                    definition: schema::trim_definition(&sql),
+                })
+            }
+            ("view", Some(sql)) => {
+                result.views.push(View {
+                    name: name,
+                    definition: schema::trim_definition(&sql),
+                })
+            }
+            _ => {}
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+fn write_history_line(xact: &Connection,
+                      version: &Version,
+                      status: MigrationStatus)
+                      -> Result<(), ScurryError> {
     try!(xact.execute(INSERT_HISTORY_LINE,
-                           &[&version.hash, &version.name, &version.version, &UTC::now()]));
+                      &[&version.hash, &version.name, &version.version, &UTC::now(),
+                        &status.name()]));
     Ok(())
 }
 
@@ -55,92 +477,461 @@ fn create_metadata_table(xact: &Connection) -> Result<(), ScurryError> {
     Ok(())
 }
 
+/// Adds any columns a metadata table created by an older version is missing.
+fn upgrade_metadata_table(conn: &Connection) -> Result<(), ScurryError> {
+    let columns = try!(query_rows(conn, "PRAGMA table_info(_scurry);", |row| {
+        let name: String = row.get(1);
+        name
+    }));
+    for &(column, definition) in METADATA_COLUMNS {
+        if !columns.iter().any(|c| c == column) {
+            try!(conn.execute_batch(&format!("ALTER TABLE _scurry ADD COLUMN {} {};",
+                                             column,
+                                             definition)));
+            info!("Added column {} to metadata table", column);
+        }
+    }
+    Ok(())
+}
+
+/// Creates the metadata table, or brings an existing one up to date.
+fn ensure_metadata_table(conn: &Connection) -> Result<(), ScurryError> {
+    if try!(history_table_exists(conn)) {
+        upgrade_metadata_table(conn)
+    } else {
+        create_metadata_table(conn)
+    }
+}
+
+fn read_history(conn: &Connection) -> Result<Vec<ScurryMetadata>, ScurryError> {
+    query_rows(conn, GET_ALL_REVISIONS, |row| {
+        ScurryMetadata {
+            id: row.get(0),
+            migration_date: row.get(1),
+            script_hash: row.get(2),
+            script_name: row.get(3),
+            script_version: row.get(4),
+            status: MigrationStatus::from_name(&row.get::<_, String>(5)),
+        }
+    })
+}
+
+/// The installed history, read without changing the database: nothing if the metadata table
+/// doesn't exist, and an older table is only brought up to date inside a savepoint that is
+/// rolled back.
+fn installed_history(conn: &Connection) -> Result<Vec<ScurryMetadata>, ScurryError> {
+    if !try!(history_table_exists(conn)) {
+        return Ok(vec![]);
+    }
+    try!(conn.execute_batch("SAVEPOINT scurry_read;"));
+    let history = upgrade_metadata_table(conn).and_then(|_| read_history(conn));
+    try!(conn.execute_batch("ROLLBACK TO scurry_read; RELEASE scurry_read;"));
+    history
+}
+
+/// How many migrations a run to `desired` would apply, read in a transaction that is rolled back
+/// so the database is left untouched.
+fn pending_count(conn: &mut Connection,
+                 versions: &[Version],
+                 desired: &DesiredVersion)
+                 -> Result<usize, ScurryError> {
+    let xact = try!(conn.transaction());
+    try!(ensure_metadata_table(&xact));
+    let history = try!(read_history(&xact));
+    Ok(util::choose_upgrade_path(versions, &history, desired).len())
+}
+
+/// Whether `version` is in the history, e.g. because another process applied it while this one
+/// was between transactions.
+fn version_installed(conn: &Connection, version: &str) -> Result<bool, ScurryError> {
+    let mut stmt = try!(conn.prepare(VERSION_INSTALLED));
+    let rows = try!(stmt.query_map(&[&version], |_| true));
+    Ok(rows.count() > 0)
+}
+
+/// Runs the seeds for `environment` that are new or have changed since they were last applied,
+/// in the caller's transaction.  Returns how many ran.
+fn apply_seeds(conn: &Connection,
+               seeds_dirs: &[String],
+               environment: Option<&str>,
+               placeholders: &Placeholders)
+               -> Result<usize, ScurryError> {
+    let seeds = try!(seeds::find_seeds(seeds_dirs, environment, Dialect::Sqlite));
+    let installed = try!(query_rows(conn, GET_SEEDS, |row| {
+        let key: String = row.get(0);
+        let hash: String = row.get(1);
+        (key, hash)
+    }));
+    let pending = seeds::pending_seeds(&seeds, &installed);
+    let scripts = try!(util::load_scripts(&pending, placeholders));
+    for (seed, contents) in pending.iter().zip(scripts.iter()) {
+        info!("Applying seed {}...", &seed.version);
+        try!(execute_script(conn, contents, |s, e| util::statement_failed(seed, s, e))
+            .map_err(|e| util::migration_failed(seed, e)));
+        try!(conn.execute(DELETE_SEED, &[&seed.version]));
+        try!(conn.execute(INSERT_SEED,
+                          &[&seed.hash, &seed.name, &seed.version, &UTC::now()]));
+    }
+    Ok(pending.len())
+}
+
 fn clear_history_table(xact: &Connection) -> Result<(), ScurryError> {
     try!(xact.execute(DELETE_HISTORY, &[]));
     Ok(())
 }
 
-fn apply_migration(xact: &mut Connection, version: &Version) -> Result<(), ScurryError> {
-    let sub_xact = try!(xact.transaction());
-    let contents = try!(util::get_file_contents(&version.path));
-    try!(sub_xact.execute_batch(&contents));
-    try!(write_history_line(&sub_xact, &version));
-    try!(sub_xact.commit());
+fn execute_script<F>(xact: &Connection, contents: &str, on_error: F) -> Result<(), ScurryError>
+    where F: Fn(&Statement, ScurryError) -> ScurryError
+{
+    for statement in sql::split_statements(contents, Dialect::Sqlite) {
+        if let Err(e) = xact.execute_batch(&statement.text) {
+            return Err(on_error(&statement, e.into()));
+        }
+    }
+    Ok(())
+}
+
+fn run_callbacks(xact: &Connection,
+                 callbacks: &Callbacks,
+                 callback: Callback)
+                 -> Result<(), ScurryError> {
+    for (path, contents) in callbacks.scripts(callback) {
+        debug!("Running {} callback {}", callback, path);
+        try!(execute_script(xact,
+                            contents,
+                            |s, e| util::callback_failed(callback, path, s, e)));
+    }
+    Ok(())
+}
+
+/// Evaluates a migration's guard query.  No rows, a `NULL` or zero count as false.
+fn guard_holds(xact: &Connection, query: &str) -> Result<bool, ScurryError> {
+    let rows = try!(query_rows(xact, query, |row| row.get_checked::<_, Option<i64>>(0)));
+    match rows.into_iter().next() {
+        Some(holds) => Ok(try!(holds).map(|h| h != 0).unwrap_or(false)),
+        None => Ok(false),
+    }
+}
+
+/// The `-- scurry:pragma name=value` directives of a script, in file order.
+fn pragmas(contents: &str) -> Result<Vec<(String, String)>, ScurryError> {
+    let valid = |s: &str| {
+        !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    };
+    Directives::parse(contents)
+        .all(PRAGMA_DIRECTIVE)
+        .into_iter()
+        .map(|arg| {
+            let mut parts = arg.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+            if valid(name) && valid(value) {
+                Ok((name.into(), value.into()))
+            } else {
+                let message = format!("Invalid pragma directive '{}', expected name=value", arg);
+                Err(ScurryError::Parse(message))
+            }
+        })
+        .collect()
+}
+
+/// Sets `pragmas` on the connection and returns what they were before, in the order they should
+/// be put back.
+fn set_pragmas(conn: &Connection,
+               pragmas: &[(String, String)])
+               -> Result<Vec<(String, String)>, ScurryError> {
+    let mut previous = vec![];
+    for &(ref name, ref value) in pragmas {
+        let current = try!(query_rows(conn, &format!("PRAGMA {};", name), |row| {
+            row.get_checked::<_, i64>(0)
+                .map(|v| v.to_string())
+                .or_else(|_| row.get_checked::<_, String>(0))
+        }));
+        if let Some(current) = current.into_iter().next() {
+            previous.push((name.clone(), try!(current)));
+        }
+        try!(conn.execute_batch(&format!("PRAGMA {} = {};", name, value)));
+        debug!("Set PRAGMA {} = {}", name, value);
+    }
+    previous.reverse();
+    Ok(previous)
+}
+
+/// Fails if any row references a parent row that doesn't exist, which a migration run with
+/// `foreign_keys` off can leave behind.
+fn check_foreign_keys(xact: &Connection) -> Result<(), ScurryError> {
+    let violations = try!(query_rows(xact, FOREIGN_KEY_CHECK, |row| {
+        let table: String = row.get(0);
+        let rowid: Option<i64> = row.get(1);
+        let parent: String = row.get(2);
+        match rowid {
+            Some(rowid) => format!("{} row {} -> {}", table, rowid, parent),
+            None => format!("{} -> {}", table, parent),
+        }
+    }));
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(ScurryError::ForeignKeyViolations(violations))
+    }
+}
+
+/// Applies a migration in its own immediate transaction, with its pragmas set beforehand since
+/// SQLite ignores `foreign_keys` inside a transaction.  The pragmas are put back afterwards
+/// whether or not it succeeded.  Returns false if another process applied it first.
+fn apply_alone(conn: &mut Connection,
+               pragmas: &[(String, String)],
+               version: &Version,
+               contents: &str,
+               callbacks: &Callbacks,
+               code: &CodeMigrations,
+               listeners: &mut Listeners)
+               -> Result<bool, ScurryError> {
+    let previous = try!(set_pragmas(conn, pragmas));
+    let result = apply_committed(conn,
+                                 version,
+                                 contents,
+                                 callbacks,
+                                 code,
+                                 listeners,
+                                 !pragmas.is_empty());
+    let restored = set_pragmas(conn, &previous);
+    let applied = try!(result);
+    try!(restored);
+    Ok(applied)
+}
+
+fn apply_committed(conn: &mut Connection,
+                   version: &Version,
+                   contents: &str,
+                   callbacks: &Callbacks,
+                   code: &CodeMigrations,
+                   listeners: &mut Listeners,
+                   foreign_key_check: bool)
+                   -> Result<bool, ScurryError> {
+    let xact = try!(conn.transaction_with_behavior(TransactionBehavior::Immediate));
+    if try!(version_installed(&xact, &version.version)) {
+        info!("Version {} was applied by another process", &version.version);
+        return Ok(false);
+    }
+    try!(apply_reported(&xact,
+                        version,
+                        contents,
+                        callbacks,
+                        code,
+                        listeners,
+                        foreign_key_check));
+    try!(xact.commit());
+    Ok(true)
+}
+
+/// Applies a migration in the caller's transaction, telling the listeners about it.
+fn apply_reported(xact: &Connection,
+                  version: &Version,
+                  contents: &str,
+                  callbacks: &Callbacks,
+                  code: &CodeMigrations,
+                  listeners: &mut Listeners,
+                  foreign_key_check: bool)
+                  -> Result<(), ScurryError> {
+    info!("Applying version {}...", &version.version);
+    try!(listeners.before_each(version));
+    let started = Instant::now();
+    let result = apply_migration(xact, version, contents, callbacks, code).and_then(|_| {
+        if foreign_key_check {
+            check_foreign_keys(xact)
+        } else {
+            Ok(())
+        }
+    });
+    if let Err(e) = result.map_err(|e| util::migration_failed(version, e)) {
+        listeners.on_error(version, &e);
+        return Err(e);
+    }
+    listeners.after_each(version, started.elapsed());
     Ok(())
 }
 
+fn apply_migration(xact: &Connection,
+                   version: &Version,
+                   contents: &str,
+                   callbacks: &Callbacks,
+                   code: &CodeMigrations)
+                   -> Result<MigrationStatus, ScurryError> {
+    if let Some(query) = util::guard(contents) {
+        if !try!(guard_holds(xact, &query)) {
+            info!("Skipped version {}, its guard was false", &version.version);
+            try!(write_history_line(xact, &version, MigrationStatus::Skipped));
+            return Ok(MigrationStatus::Skipped);
+        }
+    }
+    try!(run_callbacks(xact, callbacks, Callback::BeforeEachMigrate));
+    match version.kind {
+        MigrationKind::Sql => {
+            try!(execute_script(xact, contents, |s, e| util::statement_failed(version, s, e)));
+        }
+        MigrationKind::Rust => {
+            match code.get(&version.version) {
+                Some(&(_, ref migration)) => try!(migration.up(xact)),
+                None => return Err(ScurryError::MissingCodeMigration(version.version.clone())),
+            }
+        }
+    }
+    try!(run_callbacks(xact, callbacks, Callback::AfterEachMigrate));
+    try!(write_history_line(xact, &version, MigrationStatus::Applied));
+    Ok(MigrationStatus::Applied)
+}
+
 impl ScurryConnection for Sqlite {
     type DbConnection = Connection;
 
     fn migrate(&mut self, desired_version: DesiredVersion) -> Result<usize, ScurryError> {
-        let versions = try!(util::calculate_available_versions(&self.migrations_dir));
+        let started = Instant::now();
+        let versions = try!(self.available_versions());
         info!("Found {} migrations", versions.len());
-        let history = try!(self.get_history());
-        try!(util::verify_common_history(&versions, &history));
-        let latest_version = history.iter().last();
-        match latest_version {
-            None => {
-                info!("Schema at version 0");
-            }
-            Some(rev) => {
-                info!("Schema at version {}", rev.script_version);
+        let database = try!(database_file(&self.conn));
+        let placeholders = self.placeholders.with_builtins(METADATA_TABLE, &database);
+        let callbacks = try!(try!(util::find_callbacks(&self.locations, Dialect::Sqlite))
+            .substitute(&placeholders));
+        let backup_settings = self.backups.map(|_| self.backup_settings());
+        let per_migration = self.atomicity == Atomicity::PerMigration;
+        try!(self.conn.busy_timeout(self.busy_timeout));
+        // SQLite can't back up a database while the same connection is writing to it, so the
+        // backup is taken before the run's transaction starts.
+        if let Some(ref settings) = backup_settings {
+            if try!(pending_count(&mut self.conn, &versions, &desired_version)) == 0 {
+                debug!("Nothing to migrate, skipping backup");
+            } else if Path::new(&database).is_file() {
+                try!(backup::take(&self.conn, &database, settings));
+            } else {
+                warn!("Not backing up {}, it isn't a file", database);
             }
         }
-        let metadata_exists = try!(history_table_exists(&self.conn));
-        if !metadata_exists {
-            try!(create_metadata_table(&self.conn));
-        }
-        let upgrade_path = util::choose_upgrade_path(&versions, &latest_version, &desired_version);
-        let upgrade_len = upgrade_path.len();
-        info!("Applying {} migrations", upgrade_len);
-        for v in upgrade_path {
-            info!("Applying version {}...", &v.version);
-            try!(apply_migration(&mut self.conn, &v));
+        let (history, applied, seeded) = {
+            let Sqlite { ref mut conn,
+                         ref mut listeners,
+                         ref code_migrations,
+                         ref seeds_dirs,
+                         ref environment,
+                         .. } = *self;
+            // Taking the write lock before reading history makes a second process wait here
+            // until the first is done, then see what it applied.
+            let mut xact = try!(conn.transaction_with_behavior(TransactionBehavior::Immediate));
+            try!(ensure_metadata_table(&xact));
+            let history = try!(read_history(&xact));
+            try!(util::verify_common_history(&versions, &history));
+            match history.last() {
+                None => {
+                    info!("Schema at version 0");
+                }
+                Some(rev) => {
+                    info!("Schema at version {}", rev.script_version);
+                }
+            }
+            let upgrade_path = util::choose_upgrade_path(&versions, &history, &desired_version);
+            let scripts = try!(util::load_scripts(&upgrade_path, &placeholders));
+            let script_pragmas = try!(scripts.iter()
+                .map(|s| pragmas(s))
+                .collect::<Result<Vec<_>, ScurryError>>());
+            try!(run_callbacks(&xact, &callbacks, Callback::BeforeMigrate));
+            try!(listeners.before_migrate(&upgrade_path));
+            info!("Applying {} migrations", upgrade_path.len());
+            let mut applied = vec![];
+            let steps = upgrade_path.iter().zip(scripts.iter()).zip(script_pragmas.iter());
+            for ((v, contents), pragmas) in steps {
+                if per_migration || !pragmas.is_empty() {
+                    try!(xact.commit());
+                    if try!(apply_alone(conn,
+                                        pragmas,
+                                        v,
+                                        contents,
+                                        &callbacks,
+                                        code_migrations,
+                                        listeners)) {
+                        applied.push(*v);
+                    }
+                    xact = try!(conn.transaction_with_behavior(TransactionBehavior::Immediate));
+                } else {
+                    try!(apply_reported(&xact,
+                                        v,
+                                        contents,
+                                        &callbacks,
+                                        code_migrations,
+                                        listeners,
+                                        false));
+                    applied.push(*v);
+                }
+            }
+            let seeded = if let DesiredVersion::Latest = desired_version {
+                try!(apply_seeds(&xact,
+                                 seeds_dirs,
+                                 environment.as_ref().map(|e| e.as_str()),
+                                 &placeholders))
+            } else {
+                0
+            };
+            try!(run_callbacks(&xact, &callbacks, Callback::AfterMigrate));
+            try!(xact.commit());
+            (history, applied, seeded)
+        };
+        if let Some(ref path) = self.schema_dump {
+            try!(try!(self.schema()).write_to(path));
         }
-        Ok(upgrade_len)
+        let summary = util::summarize(&history.last(), &applied, started.elapsed());
+        self.listeners.after_migrate(&summary);
+        Ok(applied.len() + seeded)
+    }
+
+    fn lint(&self, desired_version: DesiredVersion) -> Result<Vec<LintFinding>, ScurryError> {
+        let versions = try!(self.available_versions());
+        let history = try!(installed_history(&self.conn));
+        let upgrade_path = util::choose_upgrade_path(&versions, &history, &desired_version);
+        lint::lint_versions(&upgrade_path, Dialect::Sqlite)
+    }
+
+    fn schema(&self) -> Result<Schema, ScurryError> {
+        introspect(&self.conn)
+    }
+
+    fn drift(&self) -> Result<Vec<SchemaDifference>, ScurryError> {
+        let versions = try!(self.available_versions());
+        let history = try!(installed_history(&self.conn));
+        try!(util::verify_common_history(&versions, &history));
+        let applied = util::applied_versions(&versions, &history);
+        let expected = try!(self.scratch_schema(&applied));
+        let actual = try!(introspect(&self.conn));
+        Ok(schema::diff(&expected, &actual))
+    }
+
+    fn squash(&self, desired_version: DesiredVersion) -> Result<Baseline, ScurryError> {
+        let versions = try!(self.available_versions());
+        let squashed = util::choose_upgrade_path(&versions, &[], &desired_version);
+        let schema = try!(self.scratch_schema(&squashed));
+        Baseline::new(&squashed, schema)
     }
 
     fn get_differences(&self) -> Result<Vec<HistoryDifferences>, ScurryError> {
-        let available = try!(util::calculate_available_versions(&self.migrations_dir));
+        let available = try!(self.available_versions());
         let installed = try!(self.get_history());
         Ok(util::get_history_differences(&available, &installed))
     }
 
     fn set_schema_level(&self, desired_version: DesiredVersion) -> Result<(), ScurryError> {
-        let versions = try!(util::calculate_available_versions(&self.migrations_dir));
-        let upgrade_path = util::choose_upgrade_path(&versions, &None, &desired_version);
+        let versions = try!(self.available_versions());
+        let upgrade_path = util::choose_upgrade_path(&versions, &[], &desired_version);
         try!(self.override_versions(&upgrade_path));
         Ok(())
     }
 
     fn get_history(&self) -> Result<Vec<ScurryMetadata>, ScurryError> {
-
-        let exists = try!(history_table_exists(&self.conn));
-        if !exists {
-            try!(create_metadata_table(&self.conn));
-        }
-        let mut stmt = try!(self.conn.prepare(GET_ALL_REVISIONS));
-        let revisions = try!(stmt.query_map(&[], |row| {
-            ScurryMetadata {
-                id: row.get(0),
-                migration_date: row.get(1),
-                script_hash: row.get(2),
-                script_name: row.get(3),
-                script_version: row.get(4),
-            }
-        }));
-        let mut result = vec![];
-        for revision in revisions {
-            let item = try!(revision);
-            result.push(item);
-        }
-        Ok(result)
+        try!(ensure_metadata_table(&self.conn));
+        read_history(&self.conn)
     }
 
     fn override_versions(&self, versions: &[&Version]) -> Result<(), ScurryError> {
         try!(clear_history_table(&self.conn));
         for v in versions {
-            try!(write_history_line(&self.conn, v));
+            try!(write_history_line(&self.conn, v, MigrationStatus::Applied));
         }
         Ok(())
     }
@@ -150,8 +941,114 @@ impl ScurryConnection for Sqlite {
     }
 }
 
+/// Whether `name` matches `pattern`, where `*` stands for any run of characters and `?` for any
+/// single one.
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&'*') => (0..name.len() + 1).any(|i| wildcard_match(&pattern[1..], &name[i..])),
+        Some(&p) => {
+            match name.first() {
+                Some(&n) if p == '?' || p == n => wildcard_match(&pattern[1..], &name[1..]),
+                _ => false,
+            }
+        }
+    }
+}
+
+/// The database files `spec` refers to, sorted: the files with a database extension in a
+/// directory, the files matching a pattern such as `data/*.db` (wildcards are only allowed in
+/// the file name), or else the single file named, which must exist: opening a missing file would
+/// create an empty database and migrate that instead.
+pub fn find_databases(spec: &str) -> Result<Vec<String>, ScurryError> {
+    let path = Path::new(spec);
+    if path.is_dir() {
+        let mut files = vec![];
+        for entry in try!(fs::read_dir(path)) {
+            let file = try!(entry).path();
+            let is_database = file.extension()
+                .and_then(|e| e.to_str())
+                .map(|e| DATABASE_EXTENSIONS.contains(&e))
+                .unwrap_or(false);
+            if file.is_file() && is_database {
+                files.push(try!(util::path_string(&file)));
+            }
+        }
+        files.sort();
+        return Ok(files);
+    }
+    let pattern: Vec<char> = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) if name.contains('*') || name.contains('?') => name.chars().collect(),
+        _ => return existing_database(spec).map(|db| vec![db]),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut files = vec![];
+    for entry in try!(fs::read_dir(dir)) {
+        let file = try!(entry).path();
+        let matches = file.file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| wildcard_match(&pattern, &n.chars().collect::<Vec<_>>()))
+            .unwrap_or(false);
+        if file.is_file() && matches {
+            files.push(try!(util::path_string(&file)));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The database files listed one per line in `path`.  Blank lines and lines starting with `#`
+/// are ignored; a listed file that doesn't exist is an error.
+pub fn read_database_list(path: &str) -> Result<Vec<String>, ScurryError> {
+    let contents = try!(util::get_file_contents(path));
+    contents.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(existing_database)
+        .collect()
+}
+
+fn existing_database(path: &str) -> Result<String, ScurryError> {
+    if Path::new(path).is_file() {
+        Ok(path.into())
+    } else {
+        Err(ScurryError::MissingDatabase(path.into()))
+    }
+}
+
+/// Migrates each database file in `files` to `desired_version`, as configured by `open`, which
+/// is called with each path to provide its connection.  Files are migrated on up to
+/// `options.parallelism` threads.
+pub fn migrate_files<F>(files: Vec<String>,
+                        desired_version: DesiredVersion,
+                        options: &BatchOptions,
+                        open: F)
+                        -> BatchReport
+    where F: Fn(&str) -> Result<Sqlite, ScurryError> + Send + Sync + 'static
+{
+    batch::run(files, options, move |path| {
+        let mut conn = try!(open(path));
+        let applied = try!(conn.migrate(desired_version.clone()));
+        let history = try!(conn.get_history());
+        Ok((applied, history.last().map(|h| h.script_version.clone())))
+    })
+}
+
 impl From<SqliteError> for ScurryError {
     fn from(e: SqliteError) -> ScurryError {
-        ScurryError::Sql(Box::new(e))
+        let busy = match e {
+            SqliteError::SqliteFailure(ref err, _) => {
+                err.code == ErrorCode::DatabaseBusy || err.code == ErrorCode::DatabaseLocked
+            }
+            _ => false,
+        };
+        if busy {
+            ScurryError::LockTimeout(Box::new(e))
+        } else {
+            ScurryError::Sql(Box::new(e))
+        }
     }
 }
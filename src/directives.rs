@@ -0,0 +1,64 @@
+//! `-- scurry:<name> <argument>` directives in the comment header of a script.
+//!
+//! The header is the run of blank lines and `--` comments at the top of the file; directives
+//! after the first statement are ignored.
+
+/// A single directive, e.g. `-- scurry:lint-ignore drop-column` has the name `lint-ignore` and
+/// the argument `drop-column`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directive {
+    pub name: String,
+    pub argument: String,
+    /// 1-based line the directive appears on.
+    pub line: usize,
+}
+
+const PREFIX: &'static str = "scurry:";
+
+#[derive(Debug, Clone, Default)]
+pub struct Directives(Vec<Directive>);
+
+impl Directives {
+    /// Reads the directives from the header of `contents`.
+    pub fn parse(contents: &str) -> Directives {
+        let mut directives = vec![];
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if !line.starts_with("--") {
+                break;
+            }
+            let comment = line.trim_start_matches('-').trim();
+            if !comment.starts_with(PREFIX) {
+                continue;
+            }
+            let body = &comment[PREFIX.len()..];
+            let (name, argument) = match body.find(char::is_whitespace) {
+                Some(at) => (&body[..at], body[at..].trim()),
+                None => (body, ""),
+            };
+            directives.push(Directive {
+                name: name.into(),
+                argument: argument.into(),
+                line: i + 1,
+            });
+        }
+        Directives(directives)
+    }
+
+    /// The argument of the first directive called `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|d| d.name == name).map(|d| d.argument.as_str())
+    }
+
+    /// The arguments of every directive called `name`, in file order.
+    pub fn all(&self, name: &str) -> Vec<&str> {
+        self.0.iter().filter(|d| d.name == name).map(|d| d.argument.as_str()).collect()
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<Directive> {
+        self.0.iter()
+    }
+}
@@ -1,12 +1,84 @@
 use std::io::Error as IoError;
 use std::error::Error;
+use std::fmt;
+use sql::Span;
+use callbacks::Callback;
+use scan::ScanReport;
+
+/// The ways installed history can disagree with the available migrations.
+#[derive(Debug)]
+pub enum ConsistencyError {
+    /// The database has `installed` where the migrations directory has `available`.
+    #[deprecated(note = "never produced: see UnknownVersion and HashMismatch")]
+    VersionMismatch { installed: String, available: String },
+    /// The script for `version` was changed after it was applied.
+    HashMismatch {
+        version: String,
+        installed: String,
+        available: String,
+    },
+    /// The database contains a version the migrations directory doesn't know about.
+    UnknownVersion(String),
+    /// The database stopped at `installed`, partway through the migrations squashed into
+    /// `baseline`, and can no longer be brought up to date from the baseline alone.
+    IncompleteSquash { baseline: String, installed: String },
+    /// `version` was applied but `dependency`, which it depends on, was not.
+    MissingDependency { version: String, dependency: String },
+}
 
 #[derive(Debug)]
 pub enum ScurryError {
     Io(IoError),
     Parse(String),
-    Sql(Box<Error>),
-    Consistency(String),
+    InvalidFileName { path: String, reason: String },
+    MissingPlaceholders { path: String, names: Vec<String> },
+    DuplicateVersion { version: String, paths: Vec<String> },
+    /// A migration depends on a version that doesn't exist.
+    MissingDependency { version: String, dependency: String },
+    /// Migrations that depend on each other in a loop, starting and ending with the same version.
+    DependencyCycle(Vec<String>),
+    /// Scanning turned up unparsable file names or duplicated versions.
+    InvalidMigrations(ScanReport),
+    Sql(Box<Error + Send + Sync>),
+    /// The database could not be reached or opened.
+    Connection(Box<Error + Send + Sync>),
+    LockTimeout(Box<Error + Send + Sync>),
+    Consistency(ConsistencyError),
+    /// Rows referencing missing parents, found after a SQLite migration that set pragmas.
+    ForeignKeyViolations(Vec<String>),
+    /// The schema given to `Postgres::with_schema` doesn't exist.
+    MissingSchema(String),
+    /// The current Postgres schema has no metadata table, but the one in this schema is on the
+    /// search path.  Scurry used to pick it up; it now only uses the current schema's.
+    MisplacedMetadata(String),
+    /// Postgres `drift` or `squash` was called without `Postgres::with_scratch_database`.
+    MissingScratchDatabase,
+    /// A SQLite database named for a batch run doesn't exist.
+    MissingDatabase(String),
+    /// A Rust migration due to be applied has no migration registered for its version.
+    MissingCodeMigration(String),
+    /// A `MigrationListener` stopped the run by returning this error from `before_migrate` or
+    /// `before_each`.
+    Aborted(Box<ScurryError>),
+    /// `squash` was asked for a range with no migrations in it.
+    NothingToSquash,
+    /// `Sqlite::restore` found no backups of the database.
+    NoBackups(String),
+    MigrationFailed {
+        version: String,
+        name: String,
+        path: String,
+        statement_index: Option<usize>,
+        span: Option<Span>,
+        cause: Box<ScurryError>,
+    },
+    CallbackFailed {
+        callback: Callback,
+        path: String,
+        statement_index: Option<usize>,
+        span: Option<Span>,
+        cause: Box<ScurryError>,
+    },
 }
 
 impl From<IoError> for ScurryError {
@@ -14,3 +86,169 @@ impl From<IoError> for ScurryError {
         ScurryError::Io(e)
     }
 }
+
+impl From<ConsistencyError> for ScurryError {
+    fn from(e: ConsistencyError) -> ScurryError {
+        ScurryError::Consistency(e)
+    }
+}
+
+impl fmt::Display for ConsistencyError {
+    #[allow(deprecated)]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConsistencyError::VersionMismatch { ref installed, ref available } => {
+                write!(f, "Version mismatch: {} != {}", installed, available)
+            }
+            ConsistencyError::HashMismatch { ref version, ref installed, ref available } => {
+                write!(f,
+                       "Version hash mismatch for version {}: {} != {}",
+                       version,
+                       installed,
+                       available)
+            }
+            ConsistencyError::UnknownVersion(ref version) => {
+                write!(f, "Schema contains unknown version {}", version)
+            }
+            ConsistencyError::IncompleteSquash { ref baseline, ref installed } => {
+                write!(f,
+                       "Schema at version {} predates baseline {}; apply the squashed \
+                        migrations first",
+                       installed,
+                       baseline)
+            }
+            ConsistencyError::MissingDependency { ref version, ref dependency } => {
+                write!(f,
+                       "Version {} was applied without {}, which it depends on",
+                       version,
+                       dependency)
+            }
+        }
+    }
+}
+
+impl Error for ConsistencyError {}
+
+impl ScurryError {
+    /// Whether this is, or was caused by, a timeout waiting for a lock.
+    pub fn is_lock_timeout(&self) -> bool {
+        match *self {
+            ScurryError::LockTimeout(_) => true,
+            ScurryError::MigrationFailed { ref cause, .. } |
+            ScurryError::CallbackFailed { ref cause, .. } => cause.is_lock_timeout(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ScurryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScurryError::Io(ref e) => write!(f, "I/O error: {}", e),
+            ScurryError::Parse(ref s) => write!(f, "{}", s),
+            ScurryError::InvalidFileName { ref path, ref reason } => {
+                write!(f, "Invalid migration file name {}: {}", path, reason)
+            }
+            ScurryError::MissingPlaceholders { ref path, ref names } => {
+                write!(f, "No value for placeholders {} used in {}", names.join(", "), path)
+            }
+            ScurryError::DuplicateVersion { ref version, ref paths } => {
+                write!(f, "Version {} is defined more than once: {}", version, paths.join(", "))
+            }
+            ScurryError::MissingDependency { ref version, ref dependency } => {
+                write!(f, "Version {} depends on unknown version {}", version, dependency)
+            }
+            ScurryError::DependencyCycle(ref versions) => {
+                write!(f, "Migrations depend on each other: {}", versions.join(" -> "))
+            }
+            ScurryError::InvalidMigrations(ref report) => {
+                write!(f,
+                       "Invalid migrations: {} duplicated versions, {} unparsable files",
+                       report.duplicates.len(),
+                       report.unparsable.len())
+            }
+            ScurryError::Sql(ref e) => write!(f, "Database error: {}", e),
+            ScurryError::Connection(ref e) => write!(f, "Could not connect: {}", e),
+            ScurryError::LockTimeout(ref e) => write!(f, "Timed out waiting for a lock: {}", e),
+            ScurryError::Consistency(ref e) => write!(f, "Inconsistent history: {}", e),
+            ScurryError::ForeignKeyViolations(ref rows) => {
+                write!(f, "Foreign key violations: {}", rows.join(", "))
+            }
+            ScurryError::MissingSchema(ref schema) => write!(f, "Schema {} does not exist", schema),
+            ScurryError::MissingDatabase(ref path) => write!(f, "Database {} does not exist", path),
+            ScurryError::MissingScratchDatabase => {
+                write!(f, "No scratch database to replay migrations in was configured")
+            }
+            ScurryError::MisplacedMetadata(ref schema) => {
+                write!(f,
+                       "The metadata table is in schema {0}, not the current schema; move it \
+                        with ALTER TABLE {0}._scurry SET SCHEMA or put {0} first on the search \
+                        path",
+                       schema)
+            }
+            ScurryError::MissingCodeMigration(ref version) => {
+                write!(f, "No Rust migration is registered for version {}", version)
+            }
+            ScurryError::Aborted(ref cause) => write!(f, "Migration aborted: {}", cause),
+            ScurryError::NothingToSquash => write!(f, "No migrations to squash"),
+            ScurryError::NoBackups(ref database) => {
+                write!(f, "No backups of {} to restore", database)
+            }
+            ScurryError::MigrationFailed { ref version,
+                                           ref name,
+                                           ref path,
+                                           statement_index,
+                                           span,
+                                           .. } => {
+                try!(write!(f, "Migration {} ({}) failed in {}", version, name, path));
+                if let Some(i) = statement_index {
+                    try!(write!(f, " at statement {}", i + 1));
+                }
+                if let Some(span) = span {
+                    try!(write!(f, " ({})", span));
+                }
+                Ok(())
+            }
+            ScurryError::CallbackFailed { callback, ref path, statement_index, span, .. } => {
+                try!(write!(f, "Callback {} failed in {}", callback, path));
+                if let Some(i) = statement_index {
+                    try!(write!(f, " at statement {}", i + 1));
+                }
+                if let Some(span) = span {
+                    try!(write!(f, " ({})", span));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for ScurryError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match *self {
+            ScurryError::Io(ref e) => Some(e),
+            ScurryError::Sql(ref e) |
+            ScurryError::Connection(ref e) |
+            ScurryError::LockTimeout(ref e) => Some(&**e),
+            ScurryError::Consistency(ref e) => Some(e),
+            ScurryError::Aborted(ref cause) |
+            ScurryError::MigrationFailed { ref cause, .. } |
+            ScurryError::CallbackFailed { ref cause, .. } => Some(&**cause),
+            ScurryError::Parse(_) |
+            ScurryError::InvalidFileName { .. } |
+            ScurryError::MissingPlaceholders { .. } |
+            ScurryError::DuplicateVersion { .. } |
+            ScurryError::MissingDependency { .. } |
+            ScurryError::DependencyCycle(_) |
+            ScurryError::InvalidMigrations(_) |
+            ScurryError::ForeignKeyViolations(_) |
+            ScurryError::MissingSchema(_) |
+            ScurryError::MissingDatabase(_) |
+            ScurryError::MissingScratchDatabase |
+            ScurryError::MisplacedMetadata(_) |
+            ScurryError::MissingCodeMigration(_) |
+            ScurryError::NothingToSquash |
+            ScurryError::NoBackups(_) => None,
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Orders migrations by the dependencies between them rather than by version alone.
+//!
+//! A migration can name the migrations it builds on in its header:
+//!
+//! ```sql
+//! -- scurry:depends-on 0040, 0041
+//! ```
+//!
+//! A migration without the directive depends on the one before it in version order, so a
+//! directory that never uses it is the plain linear chain it always was.  Migrations that don't
+//! depend on each other, say from different teams, may then be applied in either order.
+use std::collections::BTreeSet;
+use directives::Directives;
+use error::ScurryError;
+use versions::Version;
+
+const DEPENDS_ON_DIRECTIVE: &'static str = "depends-on";
+
+/// The versions named by a script's `depends-on` directives, which may each list several.
+pub fn dependencies(contents: &str) -> Vec<String> {
+    Directives::parse(contents)
+        .all(DEPENDS_ON_DIRECTIVE)
+        .into_iter()
+        .flat_map(|arg| arg.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|v| !v.is_empty())
+        .map(|v| v.into())
+        .collect()
+}
+
+/// Follows dependencies among `remaining` until one repeats, returning the cycle found.
+fn find_cycle(remaining: &[&Version]) -> Vec<String> {
+    let mut path: Vec<&str> = vec![];
+    let mut current = match remaining.first() {
+        Some(v) => *v,
+        None => return vec![],
+    };
+    loop {
+        if let Some(start) = path.iter().position(|p| *p == current.version) {
+            let mut cycle: Vec<String> = path[start..].iter().map(|p| (*p).into()).collect();
+            cycle.push(current.version.clone());
+            return cycle;
+        }
+        path.push(&current.version);
+        current = match current.depends_on
+            .iter()
+            .filter_map(|d| remaining.iter().find(|r| &r.version == d))
+            .next() {
+            Some(next) => *next,
+            None => return path.iter().map(|p| (*p).into()).collect(),
+        };
+    }
+}
+
+/// Fills in the implicit dependency of every migration without a `depends-on` directive and
+/// sorts `versions` so each comes after everything it depends on, breaking ties by version.
+/// Fails if a migration depends on one that doesn't exist, or on itself through others.
+pub fn order(mut versions: Vec<Version>) -> Result<Vec<Version>, ScurryError> {
+    versions.sort();
+    for i in 1..versions.len() {
+        if versions[i].depends_on.is_empty() {
+            versions[i].depends_on = vec![versions[i - 1].version.clone()];
+        }
+    }
+    for v in &versions {
+        for d in &v.depends_on {
+            if !versions.iter().any(|o| &o.version == d) {
+                return Err(ScurryError::MissingDependency {
+                    version: v.version.clone(),
+                    dependency: d.clone(),
+                });
+            }
+        }
+    }
+    let mut placed: BTreeSet<String> = BTreeSet::new();
+    let mut ordered = vec![];
+    let mut remaining: Vec<Version> = versions;
+    while !remaining.is_empty() {
+        // `remaining` stays sorted, so the first ready migration has the lowest version.
+        let ready = remaining.iter()
+            .position(|v| v.depends_on.iter().all(|d| placed.contains(d)));
+        match ready {
+            Some(i) => {
+                let v = remaining.remove(i);
+                placed.insert(v.version.clone());
+                ordered.push(v);
+            }
+            None => {
+                let stuck = remaining.iter().collect::<Vec<_>>();
+                return Err(ScurryError::DependencyCycle(find_cycle(&stuck)));
+            }
+        }
+    }
+    Ok(ordered)
+}
+
+/// Adds to `selected` every version the ones already in it depend on, directly or not.
+pub fn with_ancestors<'a>(available: &'a [Version], selected: &mut Vec<&'a Version>) {
+    let mut i = 0;
+    while i < selected.len() {
+        let current: &'a Version = selected[i];
+        for d in &current.depends_on {
+            if !selected.iter().any(|s| &s.version == d) {
+                if let Some(v) = available.iter().find(|a| &a.version == d) {
+                    selected.push(v);
+                }
+            }
+        }
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use versions::MigrationKind;
+
+    fn version(v: &str, depends_on: &[&str]) -> Version {
+        Version {
+            path: format!("{}__test.sql", v),
+            name: "test".into(),
+            hash: v.into(),
+            version: v.into(),
+            kind: MigrationKind::Sql,
+            dialect: None,
+            squashes: vec![],
+            depends_on: depends_on.iter().map(|d| (*d).into()).collect(),
+        }
+    }
+
+    fn versions(ordered: &[Version]) -> Vec<&str> {
+        ordered.iter().map(|v| v.version.as_str()).collect()
+    }
+
+    #[test]
+    fn dependencies_accept_commas_and_spaces() {
+        let contents = "-- scurry:depends-on 0040, 0041\n-- scurry:depends-on 0042 0043\nSELECT 1;";
+        assert_eq!(dependencies(contents), vec!["0040", "0041", "0042", "0043"]);
+    }
+
+    #[test]
+    fn without_directives_versions_form_a_chain() {
+        let ordered = order(vec![version("3", &[]), version("1", &[]), version("2", &[])]).unwrap();
+        assert_eq!(versions(&ordered), vec!["1", "2", "3"]);
+        assert_eq!(ordered[1].depends_on, vec!["1"]);
+        assert_eq!(ordered[2].depends_on, vec!["2"]);
+        assert!(ordered[0].depends_on.is_empty());
+    }
+
+    #[test]
+    fn dependencies_come_before_lower_versions() {
+        let ordered = order(vec![version("1", &[]), version("2", &["3"]), version("3", &["1"])])
+            .unwrap();
+        assert_eq!(versions(&ordered), vec!["1", "3", "2"]);
+    }
+
+    #[test]
+    fn independent_branches_are_ordered_by_version() {
+        let ordered = order(vec![version("1", &[]),
+                                 version("2", &["1"]),
+                                 version("3", &["1"]),
+                                 version("4", &["2", "3"])])
+            .unwrap();
+        assert_eq!(versions(&ordered), vec!["1", "2", "3", "4"]);
+    }
+
+    #[test]
+    fn unknown_dependency_is_an_error() {
+        match order(vec![version("1", &[]), version("2", &["9"])]) {
+            Err(ScurryError::MissingDependency { version, dependency }) => {
+                assert_eq!(version, "2");
+                assert_eq!(dependency, "9");
+            }
+            other => panic!("expected MissingDependency, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cycle_is_reported() {
+        match order(vec![version("1", &[]), version("2", &["3"]), version("3", &["2"])]) {
+            Err(ScurryError::DependencyCycle(cycle)) => {
+                assert_eq!(cycle, vec!["2", "3", "2"]);
+            }
+            other => panic!("expected DependencyCycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ancestors_are_added_transitively() {
+        let available = order(vec![version("1", &[]),
+                                   version("2", &["1"]),
+                                   version("3", &["1"]),
+                                   version("4", &["3"])])
+            .unwrap();
+        let mut selected = vec![&available[3]];
+        with_ancestors(&available, &mut selected);
+        let mut found = selected.iter().map(|v| v.version.as_str()).collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found, vec!["1", "3", "4"]);
+    }
+}
@@ -9,7 +9,8 @@
 //! This library is meant to be embedded in applications and called at startup to perform automatic
 //! migration.  It also supports multiple applications/single database by locking the metadata
 //! table before performing the upgrade, ensuring only one process updates the DB while the others
-//! wait for the upgrade to complete.  **Note:** Concurrent updates are not available to SQLite.
+//! wait for the upgrade to complete.  SQLite does the same by taking the database's write lock
+//! before reading the metadata table; see "SQLite Locking" below.
 //!
 //! ### Creating the Connection
 //! Use `scurry::from_postgres(conn: postgres::Connection, migrations_dir: &str)` method to create
@@ -19,14 +20,180 @@
 //! Create a `DesiredVersion` instance for the version you would like
 //! (`DesiredVersion::Specific(version)` for a specific version and `DesiredVersion::Latest` for
 //! latest) and call the `connection.migrate(DesiredVersion)` method.  The return value will be a
-//! `Result<usize, ScurryError>`, with the `usize` being the number of migrations applied.
+//! `Result<usize, ScurryError>`, with the `usize` being the number of migrations and seeds
+//! applied.
+//!
+//! ### Migrating Many Databases
+//! `Postgres::with_schema` confines a connection to one schema: the search path is set to it
+//! alone, plus any schemas given to `with_fallback_schema`, and it gets its own metadata table.  `connection::postgres::migrate_schemas`
+//! runs `migrate` for every schema in a list, opening a connection per schema through a closure
+//! and working on several at once if `BatchOptions::parallelism` allows.  The returned
+//! `BatchReport` has the outcome for each schema and groups them by the version they reached;
+//! `FailurePolicy` decides whether the first failure stops schemas that haven't started yet.
+//!
+//! The SQLite equivalent is `connection::sqlite::migrate_files`, which migrates a list of
+//! database files, such as one per customer, with a connection opened for each by a closure.
+//! `find_databases` expands a directory or a `*.db` pattern into that list.
+//!
+//! ### SQLite Backups
+//! `Sqlite::with_backups(keep)` copies the database with SQLite's online backup API before each
+//! `migrate` that has migrations to apply, so a run can be undone with `Sqlite::restore`, even one
+//! that failed after committing some migrations under `Atomicity::PerMigration`.  Backups are
+//! named after the database and a timestamp, kept next to it unless `with_backup_dir` says
+//! otherwise, and pruned to the `keep` most recent.
+//!
+//! ### Postgres Timeouts
+//! A migration stuck waiting for a lock behind a long-running query keeps holding the metadata
+//! table's lock, and every query on the tables it already touched queues up behind it.
+//! `Postgres::with_timeouts` sets `lock_timeout`, `statement_timeout` and
+//! `idle_in_transaction_session_timeout` with `SET LOCAL` for the run, and a migration can set
+//! its own in its header:
+//!
+//! ```sql
+//! -- scurry:lock-timeout 2s
+//! -- scurry:statement-timeout 10min
+//! ```
+//!
+//! A run that fails with `ScurryError::LockTimeout` is rolled back and started over, up to three
+//! times with a pause that doubles from one second; `with_lock_retries` changes both.
+//!
+//! ### SQLite Locking
+//! `migrate` on SQLite opens an immediate transaction before it reads the metadata table, so a
+//! second process migrating the same file waits for the first to finish, then finds nothing left
+//! to do.  How long it waits is set by `Sqlite::with_busy_timeout` (5 seconds by default), after
+//! which it fails with `ScurryError::LockTimeout`.  By default the whole run, callbacks and seeds
+//! included, is that one transaction and a failure leaves the database as it was.
+//! `with_atomicity(Atomicity::PerMigration)` commits after each migration instead, releasing the
+//! lock between them for databases that can't be held for the length of a long run.
+//!
+//! ### SQLite Pragmas
+//! Rebuilding a table on SQLite needs `foreign_keys` off, which has no effect inside a
+//! transaction.  A migration can list the pragmas it needs in its header:
+//!
+//! ```sql
+//! -- scurry:pragma foreign_keys=OFF
+//! -- scurry:pragma legacy_alter_table=ON
+//! ```
+//!
+//! Scurry commits the run so far, sets them, applies the migration in a transaction of its own
+//! and puts the previous values back afterwards, whether or not it succeeded.  Before committing
+//! it runs `PRAGMA foreign_key_check` and fails the migration, rolling it back, if any rows are
+//! left pointing at missing parents.
+//!
+//! ### Observing Progress
+//! Implement `MigrationListener` and register it with `with_listener` on the connection to be
+//! told when a run starts, before and after each migration, when one fails and when the run
+//! finishes.  Returning an error from `before_migrate` or `before_each` aborts the run with
+//! `ScurryError::Aborted`, wrapping that error.
 //!
 //! ### Migration Versioning
 //! Migrations in the migration directory are identified by a `.sql` extension.  The format of the
 //! filename is `<version>__<name>.sql`.  Note the double underscore.  The contents of the file
-//! will be executed in a transaction against the database.  Versions are treated as strings and
+//! will be executed in a transaction against the database, one statement at a time, so a failure
+//! can be traced back to the statement's lines in the file.  Versions are treated as strings and
 //! will be run in ascending lexographical order.
 //!
+//! ### Migration Dependencies
+//! By default each migration follows the one before it, and a database that applied a later
+//! version can't go back for an earlier one.  When several teams add migrations independently, a
+//! migration can instead declare what it builds on with `-- scurry:depends-on 0040, 0041`.
+//! Pending migrations are then applied in dependency order, lowest version first among those
+//! that are ready, and an installed migration only conflicts with a pending one it depends on.
+//! Dependencies on unknown versions and dependency cycles are reported before anything runs.
+//!
+//! ### Migration Locations
+//! Migrations can be spread over several directories (e.g. core and plugin migrations) with
+//! `with_migrations_dir`, and organised into subdirectories with `with_recursive_scan(true)`.
+//! Versions are ordered across all of them.
+//!
+//! Files that can't be parsed (`0004_single_underscore.sql`, `0005__name.SQL`) or versions
+//! defined by more than one file fail the migration with `ScurryError::InvalidMigrations`, whose
+//! report names every offending path.  Set `Locations::mode` to `ScanMode::Warn` to log and skip
+//! them instead.
+//!
+//! ### Dialect-Specific Migrations
+//! When a migration has to differ between backends, tag the file with the dialect it is for:
+//! `0005__add_index.postgres.sql` and `0005__add_index.sqlite.sql`.  Each backend picks the
+//! variant tagged for it, falling back to an untagged `0005__add_index.sql`, and records the hash
+//! of the variant it ran.  Callback scripts can be tagged the same way.
+//!
+//! ### Rust Migrations
+//! Migrations that are easier to express in code can be registered with `with_code_migration`
+//! on either backend.  They take their place among the SQL files by version, run in the same
+//! transaction a SQL migration would, and are recorded in the metadata table with the given
+//! checksum (or one derived from their version and name).
+//!
+//! ### Conditional Migrations
+//! A migration that only makes sense on some databases, say one needing an extension, can start
+//! with a guard such as `-- scurry:if SELECT EXISTS (SELECT 1 FROM pg_extension WHERE extname =
+//! 'postgis')`.  The query runs in the migration's transaction just before it; when its first
+//! column is false, `NULL` or missing, the script is not run but is still recorded, with the
+//! status `skipped`.  Skipped migrations count as installed when checking history, so databases
+//! that took different paths stay consistent, and they are not retried later.
+//!
+//! ### Seeds
+//! Data that every database should contain, or only development ones, can be kept as seed
+//! scripts in a directory registered with `with_seeds_dir`.  `S__<name>.sql` files at its top
+//! apply everywhere; those in a subdirectory apply only when `with_environment` names it, e.g.
+//! `seeds/dev/S__demo_users.sql`.  Seeds run after the versioned migrations of a `migrate` to
+//! `DesiredVersion::Latest`, are recorded in the metadata table, and run again when their
+//! contents change, so they should be written to be re-runnable.
+//!
+//! ### Callback Scripts
+//! A few file names are reserved for SQL that runs around migrations rather than as one:
+//! `beforeMigrate.sql`, `beforeEachMigrate.sql`, `afterEachMigrate.sql` and `afterMigrate.sql`.
+//! They are not versioned or recorded in the metadata table, and run on every `migrate`.  On
+//! both Postgres and SQLite they share the migration transaction.
+//!
+//! ### Placeholders
+//! Scripts may reference `${name}`, replaced with values registered through `with_placeholder`
+//! on the connection, or read from a `name = value` file with `Placeholders::read_file` and
+//! passed to `with_placeholders`.  `${scurry:table}` and `${scurry:database}` are always
+//! available.  A reference to a name without a value is an error, raised before the first
+//! pending script runs; `Placeholders::set_lenient` leaves such references alone instead, for
+//! scripts with `${...}` in string literals.  `$${name}` is a literal `${name}`.
+//! Hashes are taken over the unsubstituted text.
+//!
+//! ### Linting
+//! `ScurryConnection.lint(DesiredVersion)` checks the migrations a `migrate` would apply for
+//! statements that tend to take long exclusive locks or break running code: adding columns with
+//! defaults, non-concurrent index builds, dropped columns and tables, `NOT NULL` columns without
+//! a default, column type changes, and `BEGIN`/`COMMIT` inside a script.  `lint_migrations` does
+//! the same for every migration on disk.  A file can silence rules it knows to be safe with a
+//! header comment such as `-- scurry:lint-ignore drop-column` (or `all`).
+//!
+//! ### Schema Dumps
+//! `ScurryConnection.schema()` reads the tables, columns, constraints, indexes and views back out
+//! of the database's catalog (`pg_catalog` on Postgres, `sqlite_master` and pragmas on SQLite),
+//! and its `Display` renders them as SQL in a fixed order, so a committed dump only changes when
+//! the schema does.  `with_schema_dump(path)` writes that dump after every successful migration.
+//!
+//! `ScurryConnection.drift()` replays the installed migrations against a scratch database (an
+//! in-memory SQLite database, or on Postgres the empty database given to
+//! `with_scratch_database`, inside a transaction that is rolled back) and lists the tables,
+//! columns, constraints, indexes and views where the real database differs, catching changes
+//! made by hand that the metadata table can't see.
+//!
+//! ### Squashing
+//! `ScurryConnection.squash(DesiredVersion)` runs the migrations up to a version against the same
+//! scratch database and returns a `Baseline`: the resulting schema as a single script, headed by
+//! `-- scurry:squashes <version> <hash>` lines naming every migration it replaces.  Write it to
+//! `Baseline::file_name`, remove the squashed files, and fresh databases will run only the
+//! baseline while databases that applied the originals are verified against the listed hashes.
+//! Data inserted by the squashed migrations, functions and triggers are not carried over, and
+//! Rust migrations in the squashed range have to be unregistered by hand.
+//!
+//! ### Testing
+//! With the `testing` feature, `scurry::testing` provides `SqliteTestDb` (in memory or in a
+//! temporary file) and `PostgresTestDb` (a uniquely named database on the server named by
+//! `SCURRY_TEST_POSTGRES_URL`), each migrated on creation and removed on drop, for use as a
+//! dev-dependency in integration tests.  `check_sqlite_upgrades` and `check_postgres_upgrades`
+//! migrate a fresh database to each version, compare its schema with a snapshot kept in a
+//! directory of its own (not the migrations directory, where it would be read as a migration),
+//! then upgrade it to the latest version and compare with a database migrated there directly.
+//! They report the first version that fails or diverges.  Migrations are forward-only, so
+//! there are no down scripts to round-trip.
+//!
 //! ### Version Hashing
 //! When migrations are applied, the contents are hashed and stored in the metadata table.  Before
 //! migrating, the existing migrations are checked against the database's metadata table for
@@ -34,6 +201,11 @@
 //! will abort.  Version history can be forced by use of the `ScurryConnection.set_schema_level()`
 //! method, which will re-write history as though all history matches up to the given version.
 //!
+//! ### Errors
+//! Every fallible call returns a `ScurryError`, which implements `std::error::Error` and
+//! `Display`.  A failing script is reported as `ScurryError::MigrationFailed`, naming the
+//! version and file, with the underlying database error available through `source()`.
+//!
 #[cfg(feature = "postgres")] extern crate postgres;
 #[cfg(feature = "sqlite")] extern crate rusqlite;
 extern crate chrono;
@@ -44,19 +216,66 @@ extern crate log;
 pub mod error;
 pub mod models;
 pub mod versions;
+pub mod sql;
+pub mod listener;
+pub mod callbacks;
+pub mod placeholders;
+pub mod scan;
+pub mod directives;
+pub mod lint;
+pub mod schema;
+pub mod squash;
+pub mod graph;
+pub mod seeds;
+pub mod batch;
+#[cfg(feature = "sqlite")]
+pub mod backup;
 mod util;
 pub mod connection;
+#[cfg(feature = "testing")]
+pub mod testing;
 
-pub use util::HistoryDifferences;
+pub use util::{HistoryDifferences, Locations};
 
-pub use versions::{Version, DesiredVersion};
+pub use versions::{Version, DesiredVersion, MigrationKind};
+pub use listener::{MigrationListener, MigrationSummary};
+pub use placeholders::Placeholders;
+pub use scan::{ScanMode, ScanReport};
+pub use lint::{LintFinding, Rule};
+pub use schema::{Schema, SchemaDifference};
+pub use squash::Baseline;
 
 use error::ScurryError;
 
-/// Returns a list of versions available in the given directory.  Errors if there is an IOError,
-/// or if version information cannot be parsed from the filename.
+/// Returns a list of versions available in the given directory, including every dialect-specific
+/// variant.  Errors if there is an IOError, or if version information cannot be parsed from the
+/// filename.
 pub fn get_available_versions(migrations_dir: &str) -> Result<Vec<Version>, ScurryError> {
-    util::calculate_available_versions(migrations_dir)
+    util::calculate_available_versions(&Locations::new(migrations_dir), None)
+}
+
+/// Returns the versions found across `locations`.  With a `dialect`, only the variants a backend
+/// speaking it would apply are listed.
+pub fn get_available_versions_for(locations: &Locations,
+                                  dialect: Option<sql::Dialect>)
+                                  -> Result<Vec<Version>, ScurryError> {
+    util::calculate_available_versions(locations, dialect)
+}
+
+/// Scans `locations` and reports duplicated versions, ignored files and unparsable file names
+/// alongside the versions that were found, without failing on any of them.
+pub fn scan_migrations(locations: &Locations,
+                       dialect: Option<sql::Dialect>)
+                       -> Result<(Vec<Version>, ScanReport), ScurryError> {
+    util::scan(locations, dialect)
+}
+
+/// Lints every migration found across `locations` for the variants `dialect` would apply.
+pub fn lint_migrations(locations: &Locations,
+                       dialect: sql::Dialect)
+                       -> Result<Vec<LintFinding>, ScurryError> {
+    let versions = try!(util::calculate_available_versions(locations, Some(dialect)));
+    lint::lint_versions(&versions.iter().collect::<Vec<_>>(), dialect)
 }
 
 /// Creates a new connection for migrating Postgres databases
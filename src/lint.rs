@@ -0,0 +1,406 @@
+//! Flags migration statements that are likely to lock or rewrite large tables, or that fight
+//! with the transaction scurry already wraps each migration in.
+//!
+//! Rules can be silenced for a whole file with a header directive naming them, or `all`:
+//!
+//! ```sql
+//! -- scurry:lint-ignore drop-column, drop-table
+//! ```
+use std::fmt;
+use directives::Directives;
+use error::ScurryError;
+use sql::{self, Dialect, Span, Token, TokenKind};
+use util;
+use versions::{Version, MigrationKind};
+
+const IGNORE_DIRECTIVE: &'static str = "lint-ignore";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// `ALTER TABLE ... ADD COLUMN ... DEFAULT` rewrites the whole table before Postgres 11, and
+    /// still does for volatile defaults.
+    AddColumnDefault,
+    /// `CREATE INDEX` without `CONCURRENTLY` blocks writes for the duration of the build.  Note
+    /// that `CONCURRENTLY` can't run inside the transaction scurry uses, so such indexes have to
+    /// be built outside scurry or the rule suppressed for tables known to be small.
+    NonConcurrentIndex,
+    /// `DROP COLUMN` breaks any running code still reading the column.
+    DropColumn,
+    /// `DROP TABLE` loses data and breaks any running code still using the table.
+    DropTable,
+    /// Adding a `NOT NULL` column without a default fails on any non-empty table.
+    NotNullWithoutDefault,
+    /// Changing a column's type usually rewrites the table under an exclusive lock.
+    ColumnTypeChange,
+    /// `BEGIN`/`COMMIT`/`ROLLBACK` inside a script interfere with the transaction scurry runs
+    /// each migration in.
+    TransactionControl,
+}
+
+const ALL_RULES: [Rule; 7] = [Rule::AddColumnDefault,
+                              Rule::NonConcurrentIndex,
+                              Rule::DropColumn,
+                              Rule::DropTable,
+                              Rule::NotNullWithoutDefault,
+                              Rule::ColumnTypeChange,
+                              Rule::TransactionControl];
+
+impl Rule {
+    /// The name used to refer to the rule in `lint-ignore` directives.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Rule::AddColumnDefault => "add-column-default",
+            Rule::NonConcurrentIndex => "create-index-non-concurrent",
+            Rule::DropColumn => "drop-column",
+            Rule::DropTable => "drop-table",
+            Rule::NotNullWithoutDefault => "not-null-without-default",
+            Rule::ColumnTypeChange => "column-type-change",
+            Rule::TransactionControl => "transaction-control",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Rule> {
+        ALL_RULES.iter().cloned().find(|r| r.name() == name)
+    }
+
+    fn message(&self) -> &'static str {
+        match *self {
+            Rule::AddColumnDefault => "adding a column with a default may rewrite the table",
+            Rule::NonConcurrentIndex => "index built without CONCURRENTLY blocks writes",
+            Rule::DropColumn => "dropping a column breaks code still reading it",
+            Rule::DropTable => "dropping a table loses its data",
+            Rule::NotNullWithoutDefault => {
+                "adding a NOT NULL column without a default fails on non-empty tables"
+            }
+            Rule::ColumnTypeChange => "changing a column's type may rewrite the table",
+            Rule::TransactionControl => {
+                "transaction control conflicts with the transaction scurry runs migrations in"
+            }
+        }
+    }
+
+    fn applies_to(&self, dialect: Dialect) -> bool {
+        match *self {
+            Rule::AddColumnDefault |
+            Rule::NonConcurrentIndex |
+            Rule::ColumnTypeChange => dialect == Dialect::Postgres,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A statement that tripped a rule.
+#[derive(Debug, Clone)]
+pub struct LintFinding {
+    pub rule: Rule,
+    pub version: String,
+    pub path: String,
+    /// 0-based position of the offending statement in the script.
+    pub statement_index: usize,
+    pub span: Span,
+}
+
+impl fmt::Display for LintFinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}:{}:{}: [{}] {}",
+               self.path,
+               self.span.start.line,
+               self.span.start.column,
+               self.rule,
+               self.rule.message())
+    }
+}
+
+fn is_word(tokens: &[Token], i: usize, word: &str) -> bool {
+    tokens.get(i).map(|t| t.is_word(word)).unwrap_or(false)
+}
+
+fn has_word(tokens: &[Token], word: &str) -> bool {
+    tokens.iter().any(|t| t.is_word(word))
+}
+
+fn has_sequence(tokens: &[Token], words: &[&str]) -> bool {
+    (0..tokens.len()).any(|i| words.iter().enumerate().all(|(j, w)| is_word(tokens, i + j, w)))
+}
+
+/// Whether an `ALTER` action is `ALTER [COLUMN] <name> [SET DATA] TYPE`, so a column named
+/// `type` isn't mistaken for one.
+fn is_type_change(action: &[Token]) -> bool {
+    let mut i = if is_word(action, 1, "COLUMN") { 3 } else { 2 };
+    if is_word(action, i, "SET") && is_word(action, i + 1, "DATA") {
+        i += 2;
+    }
+    is_word(action, i, "TYPE")
+}
+
+/// Splits an `ALTER TABLE` statement into its comma-separated actions, each starting at its
+/// `ADD`/`DROP`/`ALTER` keyword.
+fn alter_table_actions(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut actions = vec![];
+    let mut depth = 0usize;
+    // Skip `ALTER TABLE`; the first action keyword follows the table name.
+    let mut start = None;
+    for (i, t) in tokens.iter().enumerate().skip(2) {
+        if t.kind == TokenKind::Symbol {
+            match t.text.as_str() {
+                "(" => depth += 1,
+                ")" => depth = depth.saturating_sub(1),
+                "," if depth == 0 => {
+                    if let Some(s) = start.take() {
+                        actions.push(&tokens[s..i]);
+                    }
+                }
+                _ => {}
+            }
+        } else if start.is_none() && depth == 0 &&
+                  (t.is_word("ADD") || t.is_word("DROP") || t.is_word("ALTER")) {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        actions.push(&tokens[s..]);
+    }
+    actions
+}
+
+const CONSTRAINT_WORDS: [&'static str; 6] = ["CONSTRAINT", "PRIMARY", "UNIQUE", "FOREIGN",
+                                             "CHECK", "EXCLUDE"];
+
+fn check_statement(tokens: &[Token], dialect: Dialect) -> Vec<Rule> {
+    let mut rules = vec![];
+    if is_word(tokens, 0, "BEGIN") || is_word(tokens, 0, "COMMIT") ||
+       is_word(tokens, 0, "ROLLBACK") || is_word(tokens, 0, "END") ||
+       (is_word(tokens, 0, "START") && is_word(tokens, 1, "TRANSACTION")) {
+        rules.push(Rule::TransactionControl);
+    }
+    if is_word(tokens, 0, "DROP") && is_word(tokens, 1, "TABLE") {
+        rules.push(Rule::DropTable);
+    }
+    if is_word(tokens, 0, "CREATE") &&
+       (is_word(tokens, 1, "INDEX") ||
+        (is_word(tokens, 1, "UNIQUE") && is_word(tokens, 2, "INDEX"))) &&
+       !has_word(tokens, "CONCURRENTLY") {
+        rules.push(Rule::NonConcurrentIndex);
+    }
+    if is_word(tokens, 0, "ALTER") && is_word(tokens, 1, "TABLE") {
+        for action in alter_table_actions(tokens) {
+            if action[0].is_word("ADD") {
+                let is_constraint = CONSTRAINT_WORDS.iter().any(|w| is_word(action, 1, w));
+                if is_constraint {
+                    continue;
+                }
+                let has_default = has_word(action, "DEFAULT");
+                if has_default {
+                    rules.push(Rule::AddColumnDefault);
+                } else if has_sequence(action, &["NOT", "NULL"]) {
+                    rules.push(Rule::NotNullWithoutDefault);
+                }
+            } else if action[0].is_word("DROP") {
+                // `COLUMN` is optional in both dialects, so anything but a constraint is one.
+                if !is_word(action, 1, "CONSTRAINT") {
+                    rules.push(Rule::DropColumn);
+                }
+            } else if action[0].is_word("ALTER") && is_type_change(action) {
+                rules.push(Rule::ColumnTypeChange);
+            }
+        }
+    }
+    rules.retain(|r| r.applies_to(dialect));
+    rules.dedup();
+    rules
+}
+
+/// Rules silenced by the script's `lint-ignore` directives; `None` means all of them.
+fn ignored_rules(contents: &str) -> Option<Vec<Rule>> {
+    let mut ignored = vec![];
+    for arg in Directives::parse(contents).all(IGNORE_DIRECTIVE) {
+        for name in arg.split(|c: char| c == ',' || c.is_whitespace()).filter(|n| !n.is_empty()) {
+            if name == "all" {
+                return None;
+            }
+            match Rule::from_name(name) {
+                Some(rule) => ignored.push(rule),
+                None => warn!("Unknown lint rule '{}' in {}", name, IGNORE_DIRECTIVE),
+            }
+        }
+    }
+    Some(ignored)
+}
+
+/// Lints the script of a single migration.
+pub fn lint_script(version: &Version, contents: &str, dialect: Dialect) -> Vec<LintFinding> {
+    let ignored = match ignored_rules(contents) {
+        Some(ignored) => ignored,
+        None => return vec![],
+    };
+    let mut findings = vec![];
+    for statement in sql::split_statements(contents, dialect) {
+        for rule in check_statement(&statement.tokens
+                                         .iter()
+                                         .filter(|t| t.is_significant())
+                                         .cloned()
+                                         .collect::<Vec<_>>(),
+                                    dialect) {
+            if !ignored.contains(&rule) {
+                findings.push(LintFinding {
+                    rule: rule,
+                    version: version.version.clone(),
+                    path: version.path.clone(),
+                    statement_index: statement.index,
+                    span: statement.span,
+                });
+            }
+        }
+    }
+    findings
+}
+
+/// Lints the scripts of `versions`.  Rust migrations are skipped.
+pub fn lint_versions(versions: &[&Version],
+                     dialect: Dialect)
+                     -> Result<Vec<LintFinding>, ScurryError> {
+    let mut findings = vec![];
+    for v in versions {
+        if v.kind == MigrationKind::Rust {
+            continue;
+        }
+        let contents = try!(util::get_file_contents(&v.path));
+        findings.extend(lint_script(v, &contents, dialect));
+    }
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(contents: &str, dialect: Dialect) -> Vec<Rule> {
+        let mut rules = vec![];
+        for statement in sql::split_statements(contents, dialect) {
+            let tokens = statement.tokens
+                .into_iter()
+                .filter(|t| t.is_significant())
+                .collect::<Vec<_>>();
+            rules.extend(check_statement(&tokens, dialect));
+        }
+        rules
+    }
+
+    fn postgres(contents: &str) -> Vec<Rule> {
+        rules(contents, Dialect::Postgres)
+    }
+
+    fn sqlite(contents: &str) -> Vec<Rule> {
+        rules(contents, Dialect::Sqlite)
+    }
+
+    #[test]
+    fn column_defaults_are_flagged_on_postgres_only() {
+        let sql = "ALTER TABLE t ADD COLUMN c int DEFAULT 0;";
+        assert_eq!(postgres(sql), vec![Rule::AddColumnDefault]);
+        assert!(sqlite(sql).is_empty());
+        assert!(postgres("ALTER TABLE t ADD COLUMN c int;").is_empty());
+        assert!(postgres("ALTER TABLE t ADD CONSTRAINT fk FOREIGN KEY (a) REFERENCES b \
+                          ON DELETE SET DEFAULT;")
+            .is_empty());
+    }
+
+    #[test]
+    fn not_null_columns_need_a_default() {
+        let sql = "ALTER TABLE t ADD COLUMN c int NOT NULL;";
+        assert_eq!(postgres(sql), vec![Rule::NotNullWithoutDefault]);
+        assert_eq!(sqlite(sql), vec![Rule::NotNullWithoutDefault]);
+        let sql = "ALTER TABLE t ADD COLUMN c int NOT NULL DEFAULT 0;";
+        assert_eq!(postgres(sql), vec![Rule::AddColumnDefault]);
+        assert!(sqlite(sql).is_empty());
+        assert!(postgres("ALTER TABLE t ADD CONSTRAINT c_check CHECK (c IS NOT NULL);")
+            .is_empty());
+    }
+
+    #[test]
+    fn indexes_must_be_concurrent_on_postgres() {
+        assert_eq!(postgres("CREATE INDEX i ON t (c);"), vec![Rule::NonConcurrentIndex]);
+        assert_eq!(postgres("CREATE UNIQUE INDEX i ON t (c);"), vec![Rule::NonConcurrentIndex]);
+        assert!(postgres("CREATE INDEX CONCURRENTLY i ON t (c);").is_empty());
+        assert!(sqlite("CREATE INDEX i ON t (c);").is_empty());
+    }
+
+    #[test]
+    fn dropped_columns_are_flagged_with_or_without_column() {
+        for sql in &["ALTER TABLE t DROP COLUMN c;",
+                     "ALTER TABLE t DROP c;",
+                     "ALTER TABLE t DROP COLUMN IF EXISTS c;"] {
+            assert_eq!(postgres(sql), vec![Rule::DropColumn]);
+            assert_eq!(sqlite(sql), vec![Rule::DropColumn]);
+        }
+        assert!(postgres("ALTER TABLE t DROP CONSTRAINT c_check;").is_empty());
+        assert!(postgres("ALTER TABLE t ALTER COLUMN c DROP NOT NULL;").is_empty());
+    }
+
+    #[test]
+    fn dropped_tables_are_flagged() {
+        assert_eq!(postgres("DROP TABLE t;"), vec![Rule::DropTable]);
+        assert_eq!(sqlite("DROP TABLE IF EXISTS t;"), vec![Rule::DropTable]);
+        assert!(postgres("DROP INDEX i;").is_empty());
+    }
+
+    #[test]
+    fn type_changes_are_matched_by_position() {
+        for sql in &["ALTER TABLE t ALTER COLUMN c TYPE bigint;",
+                     "ALTER TABLE t ALTER c SET DATA TYPE bigint;",
+                     "ALTER TABLE t ALTER COLUMN type TYPE text;"] {
+            assert_eq!(postgres(sql), vec![Rule::ColumnTypeChange]);
+            assert!(sqlite(sql).is_empty());
+        }
+        assert!(postgres("ALTER TABLE t ALTER COLUMN type SET DEFAULT 'x';").is_empty());
+        assert!(postgres("ALTER TABLE t ALTER type SET NOT NULL;").is_empty());
+        assert!(postgres("ALTER TABLE t ALTER COLUMN c SET DEFAULT 'TYPE';").is_empty());
+    }
+
+    #[test]
+    fn each_action_of_an_alter_table_is_checked() {
+        let sql = "ALTER TABLE t ADD COLUMN a int NOT NULL, DROP COLUMN b, \
+                   ALTER COLUMN c TYPE text;";
+        assert_eq!(postgres(sql),
+                   vec![Rule::NotNullWithoutDefault, Rule::DropColumn, Rule::ColumnTypeChange]);
+        let sql = "ALTER TABLE t ADD COLUMN a numeric(10, 2) DEFAULT 0, DROP CONSTRAINT x;";
+        assert_eq!(postgres(sql), vec![Rule::AddColumnDefault]);
+    }
+
+    #[test]
+    fn transaction_control_is_flagged() {
+        let sql = "BEGIN; COMMIT; START TRANSACTION; ROLLBACK; END;";
+        assert_eq!(sqlite(sql), vec![Rule::TransactionControl; 5]);
+        assert!(postgres("SELECT 1;").is_empty());
+    }
+
+    fn version() -> Version {
+        Version {
+            path: "0001__test.sql".into(),
+            name: "test".into(),
+            hash: "0001".into(),
+            version: "0001".into(),
+            kind: MigrationKind::Sql,
+            dialect: None,
+            squashes: vec![],
+            depends_on: vec![],
+        }
+    }
+
+    #[test]
+    fn ignored_rules_are_not_reported() {
+        let contents = "-- scurry:lint-ignore drop-table\nDROP TABLE t;\nALTER TABLE u DROP c;";
+        let findings = lint_script(&version(), contents, Dialect::Postgres);
+        assert_eq!(findings.iter().map(|f| f.rule).collect::<Vec<_>>(), vec![Rule::DropColumn]);
+        assert_eq!(findings[0].statement_index, 1);
+        let contents = "-- scurry:lint-ignore all\nDROP TABLE t;";
+        assert!(lint_script(&version(), contents, Dialect::Postgres).is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+//! Hooks for observing, and optionally vetoing, a migration run.
+use std::time::Duration;
+use error::ScurryError;
+use versions::Version;
+
+/// What a `migrate` run did, handed to `MigrationListener::after_migrate`.
+#[derive(Debug, Clone)]
+pub struct MigrationSummary {
+    /// Version the schema was at before migrating, `None` for an empty schema.
+    pub from_version: Option<String>,
+    /// Version the schema is at after migrating, `None` for an empty schema.
+    pub to_version: Option<String>,
+    /// Versions applied by this run, in the order they were applied.
+    pub applied: Vec<String>,
+    pub duration: Duration,
+}
+
+/// Receives lifecycle events from a migration run.  Every method has a no-op default, so
+/// implementors only override what they care about.  Returning an error from `before_migrate` or
+/// `before_each` aborts the run and rolls back whatever the backend hasn't committed; the run
+/// fails with `ScurryError::Aborted` wrapping that error.
+pub trait MigrationListener {
+    /// Called once the pending migrations are known, before any of them is applied.
+    fn before_migrate(&mut self, _pending: &[&Version]) -> Result<(), ScurryError> {
+        Ok(())
+    }
+
+    /// Called before each migration is applied.
+    fn before_each(&mut self, _version: &Version) -> Result<(), ScurryError> {
+        Ok(())
+    }
+
+    /// Called after each migration is applied successfully.
+    fn after_each(&mut self, _version: &Version, _duration: Duration) {}
+
+    /// Called when a migration fails; the run stops afterwards.
+    fn on_error(&mut self, _version: &Version, _err: &ScurryError) {}
+
+    /// Called after the run completes successfully.
+    fn after_migrate(&mut self, _summary: &MigrationSummary) {}
+}
+
+/// Fans events out to every registered listener, in registration order.
+#[derive(Default)]
+pub(crate) struct Listeners(Vec<Box<MigrationListener>>);
+
+impl Listeners {
+    pub fn add(&mut self, listener: Box<MigrationListener>) {
+        self.0.push(listener);
+    }
+
+    pub fn before_migrate(&mut self, pending: &[&Version]) -> Result<(), ScurryError> {
+        for l in &mut self.0 {
+            try!(l.before_migrate(pending).map_err(|e| ScurryError::Aborted(Box::new(e))));
+        }
+        Ok(())
+    }
+
+    pub fn before_each(&mut self, version: &Version) -> Result<(), ScurryError> {
+        for l in &mut self.0 {
+            try!(l.before_each(version).map_err(|e| ScurryError::Aborted(Box::new(e))));
+        }
+        Ok(())
+    }
+
+    pub fn after_each(&mut self, version: &Version, duration: Duration) {
+        for l in &mut self.0 {
+            l.after_each(version, duration);
+        }
+    }
+
+    pub fn on_error(&mut self, version: &Version, err: &ScurryError) {
+        for l in &mut self.0 {
+            l.on_error(version, err);
+        }
+    }
+
+    pub fn after_migrate(&mut self, summary: &MigrationSummary) {
+        for l in &mut self.0 {
+            l.after_migrate(summary);
+        }
+    }
+}
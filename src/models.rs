@@ -1,5 +1,31 @@
 use chrono::{UTC, DateTime};
 
+/// Whether a recorded migration actually ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationStatus {
+    Applied,
+    /// The migration's `-- scurry:if` guard was false, so its body was never run.
+    Skipped,
+}
+
+impl MigrationStatus {
+    /// How the status is stored in the metadata table.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MigrationStatus::Applied => "applied",
+            MigrationStatus::Skipped => "skipped",
+        }
+    }
+
+    /// Unrecognised values are read as `Applied`, the only status older tables know.
+    pub fn from_name(name: &str) -> MigrationStatus {
+        match name {
+            "skipped" => MigrationStatus::Skipped,
+            _ => MigrationStatus::Applied,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ScurryMetadata {
     pub id: i32,
@@ -7,4 +33,5 @@ pub struct ScurryMetadata {
     pub script_hash: String,
     pub script_name: String,
     pub script_version: String,
+    pub status: MigrationStatus,
 }
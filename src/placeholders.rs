@@ -0,0 +1,220 @@
+//! `${name}` substitution in migration scripts.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use error::ScurryError;
+
+/// Name of the metadata table, available to scripts as `${scurry:table}`.
+pub const TABLE_PLACEHOLDER: &'static str = "scurry:table";
+/// Name of the database being migrated, available to scripts as `${scurry:database}`.
+pub const DATABASE_PLACEHOLDER: &'static str = "scurry:database";
+
+/// Prefix reserved for built-in placeholders.
+const BUILTIN_PREFIX: &'static str = "scurry:";
+
+/// Values substituted for `${name}` references in migration and callback scripts.  Names may
+/// contain letters, digits, `_`, `-`, `.` and `:`; the `scurry:` prefix is reserved for
+/// built-ins.
+///
+/// A reference to a name without a value is an error.  In lenient mode it is left as it is
+/// instead, for scripts that contain `${...}` for other reasons, such as JSON templates.
+/// `$${name}` always stands for a literal `${name}`.
+#[derive(Debug, Clone, Default)]
+pub struct Placeholders {
+    values: BTreeMap<String, String>,
+    lenient: bool,
+}
+
+fn is_placeholder_name(s: &str) -> bool {
+    !s.is_empty() &&
+    s.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.' || c == ':')
+}
+
+impl Placeholders {
+    pub fn new() -> Placeholders {
+        Placeholders::default()
+    }
+
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.values.insert(name.into(), value.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|s| s.as_str())
+    }
+
+    /// Whether a `${name}` without a value is left as it is rather than failing the run.
+    /// Unknown `${scurry:...}` names always fail.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Reads `name = value` lines from the file at `path` into these placeholders, replacing
+    /// values already set.  Blank lines and lines starting with `#` are skipped.
+    pub fn read_file(&mut self, path: &str) -> Result<(), ScurryError> {
+        let mut contents = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut contents));
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            match (parts.next().map(|n| n.trim()), parts.next()) {
+                (Some(name), Some(value)) if is_placeholder_name(name) => {
+                    self.insert(name, value.trim())
+                }
+                _ => {
+                    return Err(ScurryError::Parse(format!("{}:{}: expected name = value",
+                                                          path,
+                                                          i + 1)))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A copy of these placeholders with the built-ins for a run against `database` filled in.
+    pub fn with_builtins(&self, table: &str, database: &str) -> Placeholders {
+        let mut p = self.clone();
+        p.insert(TABLE_PLACEHOLDER, table);
+        p.insert(DATABASE_PLACEHOLDER, database);
+        p
+    }
+
+    /// Replaces every `${name}` in `contents` that has a value and unescapes `$${name}`.  If a
+    /// referenced name has no value and must have one, returns the missing names instead, each
+    /// listed once.
+    pub fn substitute(&self, contents: &str) -> Result<String, Vec<String>> {
+        let mut out = String::with_capacity(contents.len());
+        let mut missing: Vec<String> = vec![];
+        let mut rest = contents;
+        while let Some(start) = rest.find("${") {
+            let after = &rest[start + 2..];
+            let escaped = rest[..start].ends_with('$') &&
+                          after.find('}').map_or(false, |end| is_placeholder_name(&after[..end]));
+            if escaped {
+                out.push_str(&rest[..start - 1]);
+                out.push_str("${");
+                rest = after;
+                continue;
+            }
+            match after.find('}') {
+                Some(end) if is_placeholder_name(&after[..end]) => {
+                    let name = &after[..end];
+                    out.push_str(&rest[..start]);
+                    match self.values.get(name) {
+                        Some(v) => out.push_str(v),
+                        None if !self.lenient || name.starts_with(BUILTIN_PREFIX) => {
+                            if !missing.iter().any(|m| m == name) {
+                                missing.push(name.into());
+                            }
+                        }
+                        None => out.push_str(&rest[start..start + end + 3]),
+                    }
+                    rest = &after[end + 1..];
+                }
+                _ => {
+                    out.push_str(&rest[..start + 2]);
+                    rest = after;
+                }
+            }
+        }
+        out.push_str(rest);
+        if missing.is_empty() {
+            Ok(out)
+        } else {
+            Err(missing)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    fn placeholders(pairs: &[(&str, &str)]) -> Placeholders {
+        let mut p = Placeholders::new();
+        for &(name, value) in pairs {
+            p.insert(name, value);
+        }
+        p
+    }
+
+    #[test]
+    fn values_are_substituted() {
+        let p = placeholders(&[("schema", "app"), ("owner", "admin")]);
+        assert_eq!(p.substitute("ALTER TABLE ${schema}.t OWNER TO ${owner}; -- ${schema}"),
+                   Ok("ALTER TABLE app.t OWNER TO admin; -- app".into()));
+    }
+
+    #[test]
+    fn escaped_references_are_literal() {
+        let p = placeholders(&[("schema", "app")]);
+        assert_eq!(p.substitute("SELECT '$${schema}', '${schema}';"),
+                   Ok("SELECT '${schema}', 'app';".into()));
+    }
+
+    #[test]
+    fn dollar_quotes_without_a_reference_are_unchanged() {
+        let p = placeholders(&[]);
+        let sql = "SELECT $${\"a\":1}$$::jsonb, $${ not a name }$$, $${unclosed";
+        assert_eq!(p.substitute(sql), Ok(sql.into()));
+    }
+
+    #[test]
+    fn unknown_names_fail_unless_lenient() {
+        let mut p = placeholders(&[("known", "1")]);
+        assert_eq!(p.substitute("${known} ${a} ${b} ${a}"), Err(vec!["a".into(), "b".into()]));
+        p.set_lenient(true);
+        assert_eq!(p.substitute("${known} ${a}"), Ok("1 ${a}".into()));
+    }
+
+    #[test]
+    fn missing_builtins_fail_even_when_lenient() {
+        let mut p = placeholders(&[]);
+        p.set_lenient(true);
+        assert_eq!(p.substitute("SELECT * FROM ${scurry:table};"),
+                   Err(vec![TABLE_PLACEHOLDER.into()]));
+        let p = p.with_builtins("_scurry", "app");
+        assert_eq!(p.substitute("${scurry:table} ${scurry:database}"),
+                   Ok("_scurry app".into()));
+    }
+
+    #[test]
+    fn text_that_is_not_a_reference_is_unchanged() {
+        let p = placeholders(&[]);
+        let sql = "SELECT '${}', '${a b}', '${open';";
+        assert_eq!(p.substitute(sql), Ok(sql.into()));
+    }
+
+    fn read(name: &str, contents: &str) -> Result<Placeholders, ScurryError> {
+        let path = env::temp_dir().join(format!("scurry_placeholders_{}.txt", name));
+        try!(try!(File::create(&path)).write_all(contents.as_bytes()));
+        let mut p = placeholders(&[("schema", "old"), ("kept", "yes")]);
+        let result = p.read_file(path.to_str().unwrap());
+        try!(fs::remove_file(&path));
+        result.map(|_| p)
+    }
+
+    #[test]
+    fn file_lines_are_read() {
+        let p = read("lines", "# comment\n\nschema = app\n  owner=admin = root  \n").unwrap();
+        assert_eq!(p.get("schema"), Some("app"));
+        assert_eq!(p.get("owner"), Some("admin = root"));
+        assert_eq!(p.get("kept"), Some("yes"));
+    }
+
+    #[test]
+    fn malformed_file_lines_are_errors() {
+        match read("malformed", "schema = app\nno equals sign\n") {
+            Err(ScurryError::Parse(message)) => {
+                assert!(message.ends_with(":2: expected name = value"))
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+}
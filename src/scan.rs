@@ -0,0 +1,50 @@
+//! What turned up while looking for migrations, beyond the migrations themselves.
+use std::fmt;
+
+/// How to treat problems found while scanning for migrations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanMode {
+    /// Refuse to migrate if any file is unparsable or any version is defined twice.
+    Strict,
+    /// Log a warning, skip unparsable files and keep only the first file of a duplicated version.
+    Warn,
+}
+
+impl Default for ScanMode {
+    fn default() -> ScanMode {
+        ScanMode::Strict
+    }
+}
+
+/// Files found while scanning that didn't make it into the list of versions, or shouldn't have.
+#[derive(Debug, Clone, Default)]
+pub struct ScanReport {
+    /// Versions defined by more than one file for the same dialect, with every path.
+    pub duplicates: Vec<(String, Vec<String>)>,
+    /// Files that aren't `.sql` files and were skipped.
+    pub ignored: Vec<String>,
+    /// Files that look like migrations but whose names can't be parsed, with the reason.
+    pub unparsable: Vec<(String, String)>,
+}
+
+impl ScanReport {
+    /// Whether the report contains anything that makes the set of migrations ambiguous.
+    pub fn has_errors(&self) -> bool {
+        !self.duplicates.is_empty() || !self.unparsable.is_empty()
+    }
+}
+
+impl fmt::Display for ScanReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(ref version, ref paths) in &self.duplicates {
+            try!(writeln!(f, "duplicate version {}: {}", version, paths.join(", ")));
+        }
+        for &(ref path, ref reason) in &self.unparsable {
+            try!(writeln!(f, "unparsable file {}: {}", path, reason));
+        }
+        for path in &self.ignored {
+            try!(writeln!(f, "ignored file {}", path));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,362 @@
+//! A backend-neutral description of a database's schema, introspected from the catalog and
+//! rendered as a deterministic SQL dump.
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use error::ScurryError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    /// The type as the database reports it; may be empty for untyped SQLite columns.
+    pub data_type: String,
+    pub not_null: bool,
+    pub default: Option<String>,
+}
+
+/// A table constraint.  SQLite doesn't keep constraint names, so they're derived from the table
+/// and columns there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Constraint {
+    pub name: String,
+    /// The body of the constraint, e.g. `PRIMARY KEY (id)`.
+    pub definition: String,
+}
+
+/// An index that doesn't back a constraint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Index {
+    pub name: String,
+    /// The full `CREATE INDEX` statement, without a trailing semicolon.
+    pub definition: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    pub name: String,
+    /// In the order the columns were defined.
+    pub columns: Vec<Column>,
+    pub constraints: Vec<Constraint>,
+    pub indexes: Vec<Index>,
+}
+
+impl Table {
+    pub fn new(name: &str) -> Table {
+        Table {
+            name: name.into(),
+            columns: vec![],
+            constraints: vec![],
+            indexes: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct View {
+    pub name: String,
+    /// The full `CREATE VIEW` statement, without a trailing semicolon.
+    pub definition: String,
+}
+
+/// The tables and views of a database, excluding scurry's metadata table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+    /// Names of free-standing sequences, such as those behind `serial` columns.  Always empty
+    /// for SQLite.
+    pub sequences: Vec<String>,
+    pub tables: Vec<Table>,
+    pub views: Vec<View>,
+}
+
+/// The table a `FOREIGN KEY ... REFERENCES table(...)` definition points at.
+fn referenced_table(definition: &str) -> Option<&str> {
+    definition.find("REFERENCES ").map(|at| {
+        let rest = &definition[at + "REFERENCES ".len()..];
+        let end = rest.find(|c: char| c == '(' || c.is_whitespace()).unwrap_or(rest.len());
+        &rest[..end]
+    })
+}
+
+/// Trims whitespace and trailing semicolons from a definition taken from the catalog.
+pub fn trim_definition(definition: &str) -> String {
+    definition.trim().trim_end_matches(';').trim_end().into()
+}
+
+impl Schema {
+    /// Sorts tables, views, constraints and indexes by name so dumps of the same schema are
+    /// identical.  Columns keep their defined order.
+    pub fn sort(&mut self) {
+        self.sequences.sort();
+        self.tables.sort_by(|a, b| a.name.cmp(&b.name));
+        for t in &mut self.tables {
+            t.constraints.sort_by(|a, b| a.name.cmp(&b.name));
+            t.indexes.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        self.views.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    pub fn table(&self, name: &str) -> Option<&Table> {
+        self.tables.iter().find(|t| t.name == name)
+    }
+
+    /// Returns the table called `name`, adding an empty one if it isn't there yet.
+    pub fn table_mut(&mut self, name: &str) -> &mut Table {
+        match self.tables.iter().position(|t| t.name == name) {
+            Some(i) => &mut self.tables[i],
+            None => {
+                self.tables.push(Table::new(name));
+                self.tables.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Tables in the order they can be created in: by name, except that a table comes after the
+    /// tables its foreign keys reference.  Tables in a reference cycle fall back to name order.
+    fn creation_order(&self) -> Vec<&Table> {
+        let mut ordered: Vec<&Table> = vec![];
+        let mut remaining: Vec<&Table> = self.tables.iter().collect();
+        while !remaining.is_empty() {
+            let ready = remaining.iter().position(|t| {
+                t.constraints.iter().filter_map(|c| referenced_table(&c.definition)).all(|r| {
+                    r == t.name || ordered.iter().any(|o| o.name == r) ||
+                    self.table(r).is_none()
+                })
+            });
+            ordered.push(remaining.remove(ready.unwrap_or(0)));
+        }
+        ordered
+    }
+
+    /// Writes the SQL dump of this schema to `path`, replacing anything already there.
+    pub fn write_to(&self, path: &str) -> Result<(), ScurryError> {
+        let mut f = try!(File::create(path));
+        try!(f.write_all(self.to_string().as_bytes()));
+        info!("Schema written to {}", path);
+        Ok(())
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.name));
+        if !self.data_type.is_empty() {
+            try!(write!(f, " {}", self.data_type));
+        }
+        if self.not_null {
+            try!(write!(f, " NOT NULL"));
+        }
+        if let Some(ref default) = self.default {
+            try!(write!(f, " DEFAULT {}", default));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut lines: Vec<String> = self.columns.iter().map(|c| c.to_string()).collect();
+        for c in &self.constraints {
+            lines.push(format!("CONSTRAINT {} {}", c.name, c.definition));
+        }
+        try!(writeln!(f, "CREATE TABLE {} (", self.name));
+        for (i, line) in lines.iter().enumerate() {
+            let sep = if i + 1 < lines.len() { "," } else { "" };
+            try!(writeln!(f, "    {}{}", line, sep));
+        }
+        try!(writeln!(f, ");"));
+        for i in &self.indexes {
+            try!(writeln!(f, "{};", i.definition));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for name in &self.sequences {
+            try!(writeln!(f, "CREATE SEQUENCE {};", name));
+        }
+        if !self.sequences.is_empty() {
+            first = false;
+        }
+        for t in self.creation_order() {
+            if !first {
+                try!(writeln!(f, ""));
+            }
+            first = false;
+            try!(write!(f, "{}", t));
+        }
+        for v in &self.views {
+            if !first {
+                try!(writeln!(f, ""));
+            }
+            first = false;
+            try!(writeln!(f, "{};", v.definition));
+        }
+        Ok(())
+    }
+}
+
+/// The kinds of object compared when looking for drift.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Sequence,
+    Table,
+    Column,
+    Constraint,
+    Index,
+    View,
+}
+
+impl fmt::Display for ObjectKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ObjectKind::Sequence => "sequence",
+            ObjectKind::Table => "table",
+            ObjectKind::Column => "column",
+            ObjectKind::Constraint => "constraint",
+            ObjectKind::Index => "index",
+            ObjectKind::View => "view",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A way in which an actual schema differs from the expected one.  Columns, constraints and
+/// indexes are named `table.object`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaDifference {
+    /// Expected but not present in the database.
+    Missing { kind: ObjectKind, name: String },
+    /// Present in the database but not created by any migration.
+    Extra { kind: ObjectKind, name: String },
+    /// Present in both with different definitions.
+    Changed {
+        kind: ObjectKind,
+        name: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for SchemaDifference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SchemaDifference::Missing { kind, ref name } => write!(f, "missing {} {}", kind, name),
+            SchemaDifference::Extra { kind, ref name } => write!(f, "extra {} {}", kind, name),
+            SchemaDifference::Changed { kind, ref name, ref expected, ref actual } => {
+                write!(f,
+                       "changed {} {}: expected `{}`, found `{}`",
+                       kind,
+                       name,
+                       expected,
+                       actual)
+            }
+        }
+    }
+}
+
+fn diff_items<T, N, D>(kind: ObjectKind,
+                       prefix: &str,
+                       expected: &[T],
+                       actual: &[T],
+                       name: N,
+                       describe: D,
+                       out: &mut Vec<SchemaDifference>)
+    where N: Fn(&T) -> &str,
+          D: Fn(&T) -> String
+{
+    let qualified = |item: &T| format!("{}{}", prefix, name(item));
+    for e in expected {
+        match actual.iter().find(|a| name(*a) == name(e)) {
+            None => {
+                out.push(SchemaDifference::Missing {
+                    kind: kind,
+                    name: qualified(e),
+                })
+            }
+            Some(a) => {
+                let (expected, actual) = (describe(e), describe(a));
+                if expected != actual {
+                    out.push(SchemaDifference::Changed {
+                        kind: kind,
+                        name: qualified(e),
+                        expected: expected,
+                        actual: actual,
+                    });
+                }
+            }
+        }
+    }
+    for a in actual {
+        if !expected.iter().any(|e| name(e) == name(a)) {
+            out.push(SchemaDifference::Extra {
+                kind: kind,
+                name: qualified(a),
+            });
+        }
+    }
+}
+
+/// Lists how `actual` differs from `expected`.  Column order is not compared.
+pub fn diff(expected: &Schema, actual: &Schema) -> Vec<SchemaDifference> {
+    let mut out = vec![];
+    diff_items(ObjectKind::Sequence,
+               "",
+               &expected.sequences,
+               &actual.sequences,
+               |s| s.as_str(),
+               |s| s.clone(),
+               &mut out);
+    for e in &expected.tables {
+        let a = match actual.table(&e.name) {
+            Some(a) => a,
+            None => {
+                out.push(SchemaDifference::Missing {
+                    kind: ObjectKind::Table,
+                    name: e.name.clone(),
+                });
+                continue;
+            }
+        };
+        let prefix = format!("{}.", e.name);
+        diff_items(ObjectKind::Column,
+                   &prefix,
+                   &e.columns,
+                   &a.columns,
+                   |c| c.name.as_str(),
+                   |c| c.to_string(),
+                   &mut out);
+        diff_items(ObjectKind::Constraint,
+                   &prefix,
+                   &e.constraints,
+                   &a.constraints,
+                   |c| c.name.as_str(),
+                   |c| c.definition.clone(),
+                   &mut out);
+        diff_items(ObjectKind::Index,
+                   &prefix,
+                   &e.indexes,
+                   &a.indexes,
+                   |i| i.name.as_str(),
+                   |i| i.definition.clone(),
+                   &mut out);
+    }
+    for a in &actual.tables {
+        if expected.table(&a.name).is_none() {
+            out.push(SchemaDifference::Extra {
+                kind: ObjectKind::Table,
+                name: a.name.clone(),
+            });
+        }
+    }
+    diff_items(ObjectKind::View,
+               "",
+               &expected.views,
+               &actual.views,
+               |v| v.name.as_str(),
+               |v| v.definition.clone(),
+               &mut out);
+    out
+}
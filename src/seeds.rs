@@ -0,0 +1,123 @@
+//! Seed scripts: data that is (re)applied after the versioned migrations, optionally only in
+//! some environments.
+//!
+//! Seeds live in their own directories, apart from migrations.  A file named `S__<name>.sql` at
+//! the top of a seeds directory applies everywhere; one in a subdirectory applies only when the
+//! connection's environment matches the subdirectory's name, e.g. `seeds/dev/S__demo_users.sql`.
+//! Seeds are recorded in the metadata table by hash and run again whenever their contents change.
+use std::fs;
+use std::path::Path;
+use error::ScurryError;
+use sql::Dialect;
+use util;
+use versions::{Version, MigrationKind};
+
+const SEED_PREFIX: &'static str = "S__";
+
+/// The name of the seed in `path` and whether it is tagged for `dialect`, or `None` if it isn't a
+/// seed for `dialect`.
+fn seed_name(path: &Path, dialect: Dialect) -> Option<(String, bool)> {
+    if path.extension().map(|e| e != "sql").unwrap_or(true) {
+        return None;
+    }
+    let stem = match path.file_stem().and_then(|s| s.to_str()) {
+        Some(s) => s,
+        None => return None,
+    };
+    let (stem, tag) = util::split_dialect(stem);
+    if tag.map(|t| t != dialect).unwrap_or(false) || !stem.starts_with(SEED_PREFIX) {
+        return None;
+    }
+    let name = &stem[SEED_PREFIX.len()..];
+    if name.is_empty() {
+        None
+    } else {
+        Some((name.into(), tag.is_some()))
+    }
+}
+
+/// Seeds directly in `dir`, sorted by file name.  `environment` prefixes their keys.
+fn seeds_in(dir: &Path,
+            environment: Option<&str>,
+            dialect: Dialect,
+            seeds: &mut Vec<Version>)
+            -> Result<(), ScurryError> {
+    let mut paths = vec![];
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    let mut found: Vec<Version> = vec![];
+    for path in paths {
+        let (name, tagged) = match seed_name(&path, dialect) {
+            Some(parsed) => parsed,
+            None => {
+                debug!("Skipping {}, not a seed", path.display());
+                continue;
+            }
+        };
+        let key = match environment {
+            Some(env) => format!("{}/{}", env, name),
+            None => name.clone(),
+        };
+        let path = try!(util::path_string(&path));
+        let hash = try!(util::read_migration(Path::new(&path))).hash;
+        // A dialect-tagged seed replaces the plain one of the same name.
+        if let Some(i) = found.iter().position(|s| s.version == key) {
+            if tagged {
+                found.remove(i);
+            } else {
+                continue;
+            }
+        }
+        found.push(Version {
+            path: path,
+            name: name,
+            hash: hash,
+            version: key,
+            kind: MigrationKind::Sql,
+            dialect: if tagged { Some(dialect) } else { None },
+            squashes: vec![],
+            depends_on: vec![],
+        });
+    }
+    seeds.extend(found);
+    Ok(())
+}
+
+/// The seeds in `dirs` that apply to `environment`: the shared ones first, then the ones for
+/// the environment.  Each seed's `version` is its key in the metadata table, `<name>` for shared
+/// seeds and `<environment>/<name>` otherwise.
+pub fn find_seeds(dirs: &[String],
+                  environment: Option<&str>,
+                  dialect: Dialect)
+                  -> Result<Vec<Version>, ScurryError> {
+    let mut seeds = vec![];
+    for dir in dirs {
+        try!(seeds_in(Path::new(dir), None, dialect, &mut seeds));
+    }
+    if let Some(env) = environment {
+        for dir in dirs {
+            let env_dir = Path::new(dir).join(env);
+            if env_dir.is_dir() {
+                try!(seeds_in(&env_dir, Some(env), dialect, &mut seeds));
+            }
+        }
+    }
+    Ok(seeds)
+}
+
+/// The seeds that haven't been applied, or have changed since, given the `(key, hash)` of each
+/// seed recorded in the metadata table.
+pub fn pending_seeds<'a>(seeds: &'a [Version],
+                         installed: &[(String, String)])
+                         -> Vec<&'a Version> {
+    seeds.iter()
+        .filter(|s| {
+            !installed.iter().any(|&(ref key, ref hash)| key == &s.version && hash == &s.hash)
+        })
+        .collect()
+}
@@ -0,0 +1,488 @@
+//! A small, dialect-aware SQL tokenizer used to split migration scripts into statements.
+//!
+//! This is not a parser: it only understands enough of the lexical structure of SQL (quoting,
+//! comments, dollar-quoted bodies and `BEGIN ... END` blocks) to find the semicolons that end
+//! statements.
+use std::fmt;
+
+/// The SQL dialects scurry knows how to tokenize.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Postgres,
+    Sqlite,
+}
+
+impl Dialect {
+    /// The tag used in dialect-specific file names, e.g. `0005__add_index.postgres.sql`.
+    pub fn tag(&self) -> &'static str {
+        match *self {
+            Dialect::Postgres => "postgres",
+            Dialect::Sqlite => "sqlite",
+        }
+    }
+
+    pub fn from_tag(tag: &str) -> Option<Dialect> {
+        match tag {
+            "postgres" => Some(Dialect::Postgres),
+            "sqlite" => Some(Dialect::Sqlite),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Dialect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.tag())
+    }
+}
+
+/// A 1-based line and column in a script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// The range of a script covered by a token or statement, inclusive at both ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}:{}-{}:{}",
+               self.start.line,
+               self.start.column,
+               self.end.line,
+               self.end.column)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Whitespace,
+    Comment,
+    Word,
+    Number,
+    String,
+    QuotedIdentifier,
+    Symbol,
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub span: Span,
+    offset: usize,
+}
+
+impl Token {
+    /// Whether this token is the (case-insensitive) keyword `word`.
+    pub fn is_word(&self, word: &str) -> bool {
+        self.kind == TokenKind::Word && self.text.eq_ignore_ascii_case(word)
+    }
+
+    /// Whether this token carries meaning, i.e. isn't whitespace or a comment.
+    pub fn is_significant(&self) -> bool {
+        self.kind != TokenKind::Whitespace && self.kind != TokenKind::Comment
+    }
+}
+
+/// A single statement of a script.
+#[derive(Debug, Clone)]
+pub struct Statement {
+    /// 0-based position of the statement in the script.
+    pub index: usize,
+    pub text: String,
+    pub span: Span,
+    pub tokens: Vec<Token>,
+}
+
+struct Scanner<'a> {
+    source: &'a str,
+    chars: Vec<(usize, char)>,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(source: &'a str) -> Scanner<'a> {
+        Scanner {
+            source: source,
+            chars: source.char_indices().collect(),
+            pos: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn peek(&self, ahead: usize) -> Option<char> {
+        self.chars.get(self.pos + ahead).map(|&(_, c)| c)
+    }
+
+    fn offset(&self) -> usize {
+        match self.chars.get(self.pos) {
+            Some(&(o, _)) => o,
+            None => self.source.len(),
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position {
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek(0);
+        if let Some(c) = c {
+            self.pos += 1;
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        c
+    }
+
+    fn bump_while<F>(&mut self, pred: F)
+        where F: Fn(char) -> bool
+    {
+        while let Some(c) = self.peek(0) {
+            if !pred(c) {
+                break;
+            }
+            self.bump();
+        }
+    }
+
+    /// Consumes a quoted run closed by `close`, where a doubled `close` is an escaped quote.
+    fn bump_quoted(&mut self, close: char, backslash_escapes: bool) {
+        self.bump();
+        while let Some(c) = self.bump() {
+            if backslash_escapes && c == '\\' {
+                self.bump();
+            } else if c == close {
+                if self.peek(0) == Some(close) {
+                    self.bump();
+                } else {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn bump_block_comment(&mut self, nested: bool) {
+        self.bump();
+        self.bump();
+        let mut depth = 1;
+        while let Some(c) = self.bump() {
+            if c == '*' && self.peek(0) == Some('/') {
+                self.bump();
+                depth -= 1;
+                if depth == 0 {
+                    return;
+                }
+            } else if nested && c == '/' && self.peek(0) == Some('*') {
+                self.bump();
+                depth += 1;
+            }
+        }
+    }
+
+    /// Returns the tag of a Postgres dollar quote (`$tag$`) starting at the cursor, if any.
+    fn dollar_tag(&self) -> Option<String> {
+        let mut tag = String::from("$");
+        let mut i = 1;
+        loop {
+            match self.peek(i) {
+                Some('$') => {
+                    tag.push('$');
+                    return Some(tag);
+                }
+                Some(c) if c == '_' || c.is_alphabetic() || (i > 1 && c.is_numeric()) => {
+                    tag.push(c);
+                }
+                _ => return None,
+            }
+            i += 1;
+        }
+    }
+
+    fn bump_dollar_quoted(&mut self, tag: &str) {
+        for _ in 0..tag.chars().count() {
+            self.bump();
+        }
+        loop {
+            if self.source[self.offset()..].starts_with(tag) {
+                for _ in 0..tag.chars().count() {
+                    self.bump();
+                }
+                return;
+            }
+            if self.bump().is_none() {
+                return;
+            }
+        }
+    }
+}
+
+fn is_word_start(c: char) -> bool {
+    c == '_' || c.is_alphabetic()
+}
+
+fn is_word_char(c: char, dialect: Dialect) -> bool {
+    c == '_' || c.is_alphanumeric() || (c == '$' && dialect == Dialect::Postgres)
+}
+
+/// Splits `source` into tokens.  Unterminated quotes and comments run to the end of the input.
+pub fn tokenize(source: &str, dialect: Dialect) -> Vec<Token> {
+    let mut s = Scanner::new(source);
+    let mut tokens = vec![];
+    while let Some(c) = s.peek(0) {
+        let start = s.position();
+        let offset = s.offset();
+        let kind = if c.is_whitespace() {
+            s.bump_while(|c| c.is_whitespace());
+            TokenKind::Whitespace
+        } else if c == '-' && s.peek(1) == Some('-') {
+            s.bump_while(|c| c != '\n');
+            TokenKind::Comment
+        } else if c == '/' && s.peek(1) == Some('*') {
+            s.bump_block_comment(dialect == Dialect::Postgres);
+            TokenKind::Comment
+        } else if c == '\'' {
+            s.bump_quoted('\'', false);
+            TokenKind::String
+        } else if (c == 'E' || c == 'e') && s.peek(1) == Some('\'') &&
+                  dialect == Dialect::Postgres {
+            s.bump();
+            s.bump_quoted('\'', true);
+            TokenKind::String
+        } else if c == '"' {
+            s.bump_quoted('"', false);
+            TokenKind::QuotedIdentifier
+        } else if c == '`' && dialect == Dialect::Sqlite {
+            s.bump_quoted('`', false);
+            TokenKind::QuotedIdentifier
+        } else if c == '[' && dialect == Dialect::Sqlite {
+            s.bump_quoted(']', false);
+            TokenKind::QuotedIdentifier
+        } else if c == '$' && dialect == Dialect::Postgres && s.dollar_tag().is_some() {
+            let tag = s.dollar_tag().unwrap();
+            s.bump_dollar_quoted(&tag);
+            TokenKind::String
+        } else if is_word_start(c) {
+            s.bump_while(|c| is_word_char(c, dialect));
+            TokenKind::Word
+        } else if c.is_numeric() {
+            s.bump_while(|c| c.is_alphanumeric() || c == '.');
+            TokenKind::Number
+        } else {
+            s.bump();
+            TokenKind::Symbol
+        };
+        // `bump` has already moved past the last character, so step the end column back onto it.
+        let end = Position {
+            line: s.line,
+            column: if s.column > 1 { s.column - 1 } else { 1 },
+        };
+        tokens.push(Token {
+            kind: kind,
+            text: source[offset..s.offset()].into(),
+            span: Span {
+                start: start,
+                end: end,
+            },
+            offset: offset,
+        });
+    }
+    tokens
+}
+
+/// Words after which an operand is expected, so an `END` or `BEGIN` following one of them is a
+/// column named `end` or `begin` rather than a keyword.
+const OPERAND_KEYWORDS: [&'static str; 12] = ["AND", "AS", "BY", "ELSE", "NOT", "ON", "OR",
+                                              "SELECT", "SET", "THEN", "WHEN", "WHERE"];
+
+/// Whether `token` can be the last token of an expression or statement, which is where the
+/// `END` of a block comes.
+fn ends_operand(token: &Token) -> bool {
+    match token.kind {
+        TokenKind::Symbol => token.text == ")" || token.text == ";",
+        TokenKind::Word => !OPERAND_KEYWORDS.iter().any(|w| token.is_word(w)),
+        _ => true,
+    }
+}
+
+/// Whether `token` opens a `BEGIN ... END` or `CASE ... END` block, given the significant tokens
+/// either side of it in the statement.
+fn opens_block(previous: Option<&Token>, token: &Token, next: Option<&Token>) -> bool {
+    if token.is_word("CASE") {
+        // `case` can only be an identifier when qualified, e.g. `t.case`.
+        previous.map_or(true, |p| p.kind != TokenKind::Symbol || p.text != ".")
+    } else if token.is_word("BEGIN") {
+        // A leading BEGIN opens a transaction rather than a block, and a block's body starts
+        // with a statement's keyword.
+        previous.map_or(false, ends_operand) && next.map_or(false, |n| n.kind == TokenKind::Word)
+    } else {
+        false
+    }
+}
+
+/// Splits a script into its statements.  Semicolons inside strings, comments, dollar-quoted
+/// bodies and `BEGIN ... END` blocks (e.g. SQLite trigger bodies) don't end a statement.
+/// Statements consisting only of comments are dropped.
+pub fn split_statements(source: &str, dialect: Dialect) -> Vec<Statement> {
+    let tokens = tokenize(source, dialect);
+    let mut statements = vec![];
+    let mut current: Vec<Token> = vec![];
+    let mut depth = 0usize;
+    let mut previous: Option<&Token> = None;
+    for (i, token) in tokens.iter().enumerate() {
+        let next = tokens[i + 1..].iter().find(|t| t.is_significant());
+        if opens_block(previous, token, next) {
+            depth += 1;
+        } else if token.is_word("END") && depth > 0 && previous.map_or(false, ends_operand) {
+            depth -= 1;
+        }
+        if token.is_significant() {
+            previous = Some(token);
+        }
+        let terminator = token.kind == TokenKind::Symbol && token.text == ";" && depth == 0;
+        current.push(token.clone());
+        if terminator {
+            push_statement(source, &mut statements, &mut current);
+            previous = None;
+        }
+    }
+    push_statement(source, &mut statements, &mut current);
+    statements
+}
+
+fn push_statement(source: &str, statements: &mut Vec<Statement>, tokens: &mut Vec<Token>) {
+    let taken = ::std::mem::replace(tokens, vec![]);
+    let first = taken.iter().position(|t| t.is_significant());
+    let last = taken.iter().rposition(|t| t.is_significant());
+    if let (Some(first), Some(last)) = (first, last) {
+        // A lone semicolon isn't worth sending to the database.
+        if first == last && taken[first].text == ";" {
+            return;
+        }
+        let start = taken[first].offset;
+        let end = taken[last].offset + taken[last].text.len();
+        let span = Span {
+            start: taken[first].span.start,
+            end: taken[last].span.end,
+        };
+        statements.push(Statement {
+            index: statements.len(),
+            text: source[start..end].into(),
+            span: span,
+            tokens: taken[first..last + 1].to_vec(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(source: &str, dialect: Dialect) -> Vec<String> {
+        split_statements(source, dialect).into_iter().map(|s| s.text).collect()
+    }
+
+    #[test]
+    fn semicolons_in_strings_and_quoted_identifiers_are_kept() {
+        let source = "INSERT INTO t VALUES ('a;b', 'it''s;');\nSELECT \"x;y\" FROM t;";
+        assert_eq!(texts(source, Dialect::Postgres),
+                   vec!["INSERT INTO t VALUES ('a;b', 'it''s;');", "SELECT \"x;y\" FROM t;"]);
+        assert_eq!(texts("SELECT `a;b`, [c;d] FROM t; SELECT 2;", Dialect::Sqlite),
+                   vec!["SELECT `a;b`, [c;d] FROM t;", "SELECT 2;"]);
+    }
+
+    #[test]
+    fn escape_strings_allow_backslashed_quotes() {
+        assert_eq!(texts("SELECT E'\\';'; SELECT 2;", Dialect::Postgres),
+                   vec!["SELECT E'\\';';", "SELECT 2;"]);
+    }
+
+    #[test]
+    fn unterminated_string_runs_to_the_end() {
+        assert_eq!(texts("SELECT 'abc; SELECT 2;", Dialect::Postgres),
+                   vec!["SELECT 'abc; SELECT 2;"]);
+    }
+
+    #[test]
+    fn dollar_quoted_bodies_are_one_statement() {
+        let source = "CREATE FUNCTION f() RETURNS int AS $body$ BEGIN RETURN 1; END; $body$ \
+                      LANGUAGE plpgsql;\nSELECT $$a;b$$;";
+        let statements = texts(source, Dialect::Postgres);
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0].ends_with("LANGUAGE plpgsql;"));
+        assert_eq!(statements[1], "SELECT $$a;b$$;");
+    }
+
+    #[test]
+    fn block_comments_nest_on_postgres_only() {
+        let source = "/* outer /* inner; */ still; */ SELECT 1; -- trailing; comment\nSELECT 2;";
+        assert_eq!(texts(source, Dialect::Postgres), vec!["SELECT 1;", "SELECT 2;"]);
+        assert_eq!(texts("/* outer /* inner */ SELECT 1;", Dialect::Sqlite), vec!["SELECT 1;"]);
+        assert!(texts("/* outer /* inner */ SELECT 1;", Dialect::Postgres).is_empty());
+    }
+
+    #[test]
+    fn comment_only_statements_are_dropped() {
+        assert!(texts("-- nothing\n;\n/* here */", Dialect::Sqlite).is_empty());
+    }
+
+    #[test]
+    fn trigger_bodies_are_one_statement() {
+        let source = "CREATE TRIGGER t AFTER INSERT ON a FOR EACH ROW BEGIN \
+                      UPDATE b SET n = n + 1; DELETE FROM c; END;\nSELECT 1;";
+        let statements = texts(source, Dialect::Sqlite);
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0].ends_with("DELETE FROM c; END;"));
+    }
+
+    #[test]
+    fn case_expressions_close_with_end() {
+        let source = "SELECT CASE WHEN x THEN 1 ELSE 2 END FROM t; SELECT 2;";
+        assert_eq!(texts(source, Dialect::Postgres).len(), 2);
+        let source = "CREATE TRIGGER t AFTER INSERT ON a BEGIN \
+                      UPDATE b SET n = CASE WHEN n > 0 THEN 1 END; END; SELECT 1;";
+        assert_eq!(texts(source, Dialect::Sqlite).len(), 2);
+    }
+
+    #[test]
+    fn leading_begin_is_a_transaction() {
+        assert_eq!(texts("BEGIN; SELECT 1; COMMIT;", Dialect::Sqlite),
+                   vec!["BEGIN;", "SELECT 1;", "COMMIT;"]);
+        assert_eq!(texts("BEGIN TRANSACTION; SELECT 1;", Dialect::Sqlite).len(), 2);
+    }
+
+    #[test]
+    fn begin_end_and_case_as_identifiers_are_not_blocks() {
+        let source = "CREATE TABLE period (begin INT, end INT); SELECT p.case FROM period p; \
+                      SELECT begin, end FROM period;";
+        assert_eq!(texts(source, Dialect::Sqlite).len(), 3);
+        let source = "CREATE TRIGGER t AFTER INSERT ON period BEGIN \
+                      UPDATE period SET end = new.begin WHERE end IS NULL; END; SELECT 1;";
+        assert_eq!(texts(source, Dialect::Sqlite).len(), 2);
+    }
+
+    #[test]
+    fn statements_carry_their_index_and_span() {
+        let statements = split_statements("SELECT 1;\n  SELECT 2;", Dialect::Sqlite);
+        assert_eq!(statements[1].index, 1);
+        assert_eq!(statements[1].span.to_string(), "2:3-2:11");
+    }
+}
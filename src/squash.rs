@@ -0,0 +1,98 @@
+//! Baselines: a single migration standing in for every migration up to a version.
+//!
+//! A baseline is the schema those migrations produce, dumped as SQL, with a header listing the
+//! version and hash of each migration it replaces:
+//!
+//! ```sql
+//! -- scurry:squashes 0001 3f786850e387550fdab836ed7e6dc881de23001b
+//! -- scurry:squashes 0002 89e6c98d92887913cadf06b2adb97f26cde4849b
+//! ```
+//!
+//! The schema is built in a scratch database, never the one being migrated, so the baseline only
+//! holds what the migrations create.  The baseline takes the version of the last migration it
+//! squashes.  Fresh databases run only
+//! the baseline, while databases that already ran the originals are checked against the listed
+//! hashes and carry on from there.
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use directives::Directives;
+use error::ScurryError;
+use schema::Schema;
+use versions::{Version, MigrationKind};
+
+const SQUASHES_DIRECTIVE: &'static str = "squashes";
+
+/// The `(version, hash)` pairs listed by a script's `squashes` directives.
+pub fn squashed_versions(contents: &str) -> Vec<(String, String)> {
+    Directives::parse(contents)
+        .all(SQUASHES_DIRECTIVE)
+        .into_iter()
+        .filter_map(|arg| {
+            let mut parts = arg.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(version), Some(hash)) => Some((version.into(), hash.into())),
+                _ => {
+                    warn!("Ignoring malformed {} directive '{}'", SQUASHES_DIRECTIVE, arg);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// A squashed migration, ready to be written out.
+#[derive(Debug, Clone)]
+pub struct Baseline {
+    /// Version of the last squashed migration, which the baseline file takes on.
+    pub version: String,
+    pub squashes: Vec<(String, String)>,
+    /// Paths of the squashed migration files, which the baseline makes redundant.
+    pub replaced: Vec<String>,
+    pub schema: Schema,
+}
+
+impl Baseline {
+    /// A baseline for `versions`, which produced `schema`.  Errors if there is nothing to squash.
+    pub fn new(versions: &[&Version], schema: Schema) -> Result<Baseline, ScurryError> {
+        let version = match versions.last() {
+            Some(v) => v.version.clone(),
+            None => return Err(ScurryError::NothingToSquash),
+        };
+        Ok(Baseline {
+            version: version,
+            squashes: versions.iter().map(|v| (v.version.clone(), v.hash.clone())).collect(),
+            replaced: versions.iter()
+                .filter(|v| v.kind == MigrationKind::Sql)
+                .map(|v| v.path.clone())
+                .collect(),
+            schema: schema,
+        })
+    }
+
+    /// The migration file name for this baseline, e.g. `0042__baseline.sql`.
+    pub fn file_name(&self, name: &str) -> String {
+        format!("{}__{}.sql", self.version, name)
+    }
+
+    pub fn write_to(&self, path: &str) -> Result<(), ScurryError> {
+        let mut f = try!(File::create(path));
+        try!(f.write_all(self.to_string().as_bytes()));
+        info!("Baseline written to {}", path);
+        Ok(())
+    }
+}
+
+impl fmt::Display for Baseline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f,
+                      "-- Baseline of {} migrations up to version {}.",
+                      self.squashes.len(),
+                      self.version));
+        for &(ref version, ref hash) in &self.squashes {
+            try!(writeln!(f, "-- scurry:{} {} {}", SQUASHES_DIRECTIVE, version, hash));
+        }
+        try!(writeln!(f, ""));
+        write!(f, "{}", self.schema)
+    }
+}
@@ -0,0 +1,485 @@
+//! Freshly migrated throwaway databases for integration tests, enabled with the `testing`
+//! feature.
+//!
+//! Each helper creates a new database, migrates it, and hands back the underlying connection.
+//! The database is removed again when the helper is dropped, so every test gets its own:
+//!
+//! ```rust,ignore
+//! let db = scurry::testing::SqliteTestDb::in_memory("./migrations").unwrap();
+//! db.conn().execute("INSERT INTO users (name) VALUES ('test')", &[]).unwrap();
+//! ```
+//!
+//! `check_sqlite_upgrades` and `check_postgres_upgrades` migrate a database to every version,
+//! check its schema there against a committed snapshot, and then check that upgrading it to the
+//! latest version gives the same schema as migrating a fresh database.
+//!
+//! Postgres databases are created on the server named by `SCURRY_TEST_POSTGRES_URL`, which must
+//! point at a database the user can connect to and has `CREATEDB` rights on (e.g. the
+//! `scurry_test` database set up by the scripts in `pg/`).
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+use error::ScurryError;
+use versions::DesiredVersion;
+use connection::ScurryConnection;
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A name no other test in this or a concurrent run will pick.
+fn unique_name(prefix: &str) -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format!("{}_{}_{}_{}",
+            prefix,
+            secs,
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst))
+}
+
+/// Whether `check_sqlite_upgrades` and `check_postgres_upgrades` compare against the schema
+/// snapshots or write them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotMode {
+    /// Fail on the first version whose schema differs from its snapshot, or has none.
+    Verify,
+    /// Write a snapshot for every version, replacing those already there.
+    Record,
+}
+
+/// The first version whose migration failed or left a schema other than its snapshot, or that
+/// can't be upgraded to the latest version cleanly.
+#[derive(Debug)]
+pub enum UpgradeFailure {
+    /// The snapshots directory `path` is, or is inside, the migrations directory, where the
+    /// snapshots would be read as migrations.
+    SnapshotsInMigrations { path: String },
+    /// Migrating a fresh database up to `version` failed.
+    Failed { version: String, error: ScurryError },
+    /// There is no snapshot for `version` at `path`.
+    MissingSnapshot { version: String, path: String },
+    /// The schema at `version` differs from the snapshot at `path`, first at 1-based `line`.
+    Diverged {
+        version: String,
+        path: String,
+        line: usize,
+        expected: String,
+        actual: String,
+    },
+    /// Upgrading a database at version `from` to the latest version failed.
+    UpgradeFailed { from: String, error: ScurryError },
+    /// Upgrading a database at version `from` to the latest version left a schema different from
+    /// a fresh database's, first at 1-based `line`.
+    UpgradeDiverged {
+        from: String,
+        line: usize,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for UpgradeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UpgradeFailure::SnapshotsInMigrations { ref path } => {
+                write!(f, "Schema snapshots in {} would be read as migrations", path)
+            }
+            UpgradeFailure::Failed { ref version, ref error } => {
+                write!(f, "Migrating to version {} failed: {}", version, error)
+            }
+            UpgradeFailure::MissingSnapshot { ref version, ref path } => {
+                write!(f, "No schema snapshot for version {} at {}", version, path)
+            }
+            UpgradeFailure::Diverged { ref version, ref path, line, ref expected, ref actual } => {
+                write!(f,
+                       "Schema at version {} differs from {} at line {}:\n  expected: {}\n  \
+                        actual:   {}",
+                       version,
+                       path,
+                       line,
+                       expected,
+                       actual)
+            }
+            UpgradeFailure::UpgradeFailed { ref from, ref error } => {
+                write!(f, "Upgrading from version {} to the latest failed: {}", from, error)
+            }
+            UpgradeFailure::UpgradeDiverged { ref from, line, ref expected, ref actual } => {
+                write!(f,
+                       "Upgrading from version {} differs from a fresh database at line {}:\n  \
+                        expected: {}\n  actual:   {}",
+                       from,
+                       line,
+                       expected,
+                       actual)
+            }
+        }
+    }
+}
+
+/// The first line at which `expected` and `actual` differ, with both lines (empty past the end
+/// of either).
+fn first_difference(expected: &str, actual: &str) -> Option<(usize, String, String)> {
+    let (mut expected, mut actual) = (expected.lines(), actual.lines());
+    let mut line = 0;
+    loop {
+        line += 1;
+        match (expected.next(), actual.next()) {
+            (None, None) => return None,
+            (e, a) if e == a => continue,
+            (e, a) => {
+                return Some((line, e.unwrap_or("").into(), a.unwrap_or("").into()));
+            }
+        }
+    }
+}
+
+/// Whether `dir` is `parent` or somewhere below it.  Falls back to comparing the paths as
+/// written when either doesn't exist yet.
+fn is_within(dir: &str, parent: &str) -> bool {
+    match (fs::canonicalize(dir), fs::canonicalize(parent)) {
+        (Ok(dir), Ok(parent)) => dir.starts_with(parent),
+        _ => Path::new(dir).starts_with(parent),
+    }
+}
+
+/// For every version N, migrates a fresh database to N and compares its schema with the snapshot
+/// `<snapshots_dir>/<N>.sql`, a dump as written by `Schema::write_to`, then upgrades it to the
+/// latest version and compares the result with a database migrated there directly.  Snapshots
+/// live in a directory of their own, committed and reviewed along with the migrations, so they
+/// are the expectation each version is held to; `SnapshotMode::Record` writes them.  `open`
+/// provides an unmigrated database, along with anything that has to stay alive while it's in
+/// use.  Returns the versions checked.
+///
+/// Migrations are forward-only, so there are no down scripts to round-trip; the upgrade from
+/// each version stands in for that check.  Every version costs a database migrated all the way,
+/// so the check grows with the square of the number of migrations.
+fn check_upgrades<C, G, F>(migrations_dir: &str,
+                           snapshots_dir: &str,
+                           mode: SnapshotMode,
+                           mut open: F)
+                           -> Result<Vec<String>, UpgradeFailure>
+    where C: ScurryConnection,
+          F: FnMut() -> Result<(C, G), ScurryError>
+{
+    if is_within(snapshots_dir, migrations_dir) {
+        return Err(UpgradeFailure::SnapshotsInMigrations { path: snapshots_dir.into() });
+    }
+    let setup = |e: ScurryError| {
+        UpgradeFailure::Failed {
+            version: "0".into(),
+            error: e,
+        }
+    };
+    let (versions, latest) = {
+        let (mut fresh, _guard) = try!(open().map_err(&setup));
+        try!(fresh.migrate(DesiredVersion::Latest).map_err(&setup));
+        let versions = try!(fresh.get_history().map_err(&setup))
+            .into_iter()
+            .map(|h| h.script_version)
+            .collect::<Vec<_>>();
+        (versions, try!(fresh.schema().map_err(&setup)).to_string())
+    };
+    if mode == SnapshotMode::Record {
+        try!(fs::create_dir_all(snapshots_dir).map_err(|e| setup(e.into())));
+    }
+    let mut checked = vec![];
+    for version in versions {
+        let failed = |e: ScurryError| {
+            UpgradeFailure::Failed {
+                version: version.clone(),
+                error: e,
+            }
+        };
+        let (mut conn, _guard) = try!(open().map_err(&failed));
+        try!(conn.migrate(DesiredVersion::Specific(version.clone())).map_err(&failed));
+        let actual = try!(conn.schema().map_err(&failed)).to_string();
+        let path = Path::new(snapshots_dir).join(format!("{}.sql", version));
+        let path_name = path.to_string_lossy().into_owned();
+        match mode {
+            SnapshotMode::Record => {
+                try!(File::create(&path)
+                    .and_then(|mut f| f.write_all(actual.as_bytes()))
+                    .map_err(|e| failed(e.into())));
+                debug!("Recorded schema snapshot {}", path_name);
+            }
+            SnapshotMode::Verify => {
+                let mut expected = String::new();
+                if File::open(&path).and_then(|mut f| f.read_to_string(&mut expected)).is_err() {
+                    return Err(UpgradeFailure::MissingSnapshot {
+                        version: version,
+                        path: path_name,
+                    });
+                }
+                if let Some((line, expected, actual)) = first_difference(&expected, &actual) {
+                    return Err(UpgradeFailure::Diverged {
+                        version: version,
+                        path: path_name,
+                        line: line,
+                        expected: expected,
+                        actual: actual,
+                    });
+                }
+                debug!("Schema at {} matches its snapshot", version);
+            }
+        }
+        let upgrade_failed = |e: ScurryError| {
+            UpgradeFailure::UpgradeFailed {
+                from: version.clone(),
+                error: e,
+            }
+        };
+        try!(conn.migrate(DesiredVersion::Latest).map_err(&upgrade_failed));
+        let upgraded = try!(conn.schema().map_err(&upgrade_failed)).to_string();
+        if let Some((line, expected, actual)) = first_difference(&latest, &upgraded) {
+            return Err(UpgradeFailure::UpgradeDiverged {
+                from: version,
+                line: line,
+                expected: expected,
+                actual: actual,
+            });
+        }
+        checked.push(version);
+    }
+    Ok(checked)
+}
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::{SqliteTestDb, check_sqlite_upgrades};
+#[cfg(feature = "postgres")]
+pub use self::postgres::{PostgresTestDb, ADMIN_URL_VAR, check_postgres_upgrades};
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use rusqlite::Connection;
+    use connection::sqlite::{self, Sqlite};
+    use super::*;
+
+    /// A migrated SQLite database, in memory or in a temporary file that is deleted on drop.
+    pub struct SqliteTestDb {
+        conn: Option<Connection>,
+        path: Option<PathBuf>,
+    }
+
+    impl SqliteTestDb {
+        /// An in-memory database migrated to the latest version.
+        pub fn in_memory(migrations_dir: &str) -> Result<SqliteTestDb, ScurryError> {
+            SqliteTestDb::in_memory_with(migrations_dir, DesiredVersion::Latest, |s| s)
+        }
+
+        /// An in-memory database migrated to `version`, after `configure` has had a chance to
+        /// register placeholders, code migrations and the like.
+        pub fn in_memory_with<F>(migrations_dir: &str,
+                                 version: DesiredVersion,
+                                 configure: F)
+                                 -> Result<SqliteTestDb, ScurryError>
+            where F: FnOnce(Sqlite) -> Sqlite
+        {
+            let conn = try!(Connection::open_in_memory());
+            let conn = try!(migrate(conn, migrations_dir, version, configure));
+            Ok(SqliteTestDb {
+                conn: Some(conn),
+                path: None,
+            })
+        }
+
+        /// A database in a new file under the system temp directory, migrated to the latest
+        /// version.  Useful when the code under test opens its own connections.
+        pub fn temp_file(migrations_dir: &str) -> Result<SqliteTestDb, ScurryError> {
+            SqliteTestDb::temp_file_with(migrations_dir, DesiredVersion::Latest, |s| s)
+        }
+
+        /// A temporary file database migrated to `version`; see `in_memory_with`.
+        pub fn temp_file_with<F>(migrations_dir: &str,
+                                 version: DesiredVersion,
+                                 configure: F)
+                                 -> Result<SqliteTestDb, ScurryError>
+            where F: FnOnce(Sqlite) -> Sqlite
+        {
+            let path = env::temp_dir().join(format!("{}.db", unique_name("scurry_test")));
+            // Own the path before migrating so a failed migration still cleans up the file.
+            let mut db = SqliteTestDb {
+                conn: None,
+                path: Some(path.clone()),
+            };
+            let conn = try!(Connection::open(&path));
+            db.conn = Some(try!(migrate(conn, migrations_dir, version, configure)));
+            Ok(db)
+        }
+
+        pub fn conn(&self) -> &Connection {
+            self.conn.as_ref().unwrap()
+        }
+
+        /// The database file, or `None` for in-memory databases.
+        pub fn path(&self) -> Option<&Path> {
+            self.path.as_ref().map(|p| p.as_path())
+        }
+    }
+
+    fn migrate<F>(conn: Connection,
+                  migrations_dir: &str,
+                  version: DesiredVersion,
+                  configure: F)
+                  -> Result<Connection, ScurryError>
+        where F: FnOnce(Sqlite) -> Sqlite
+    {
+        let mut scurry = configure(sqlite::establish(conn, migrations_dir));
+        try!(scurry.migrate(version));
+        Ok(scurry.take_connection())
+    }
+
+    /// Checks every version's schema against its snapshot in `snapshots_dir`, and the
+    /// upgrade from it to the latest version, using in-memory databases.  `configure` is applied to
+    /// each connection before migrating.
+    pub fn check_sqlite_upgrades<F>(migrations_dir: &str,
+                                    snapshots_dir: &str,
+                                    mode: SnapshotMode,
+                                    configure: F)
+                                    -> Result<Vec<String>, UpgradeFailure>
+        where F: Fn(Sqlite) -> Sqlite
+    {
+        check_upgrades(migrations_dir, snapshots_dir, mode, || {
+            let conn = try!(Connection::open_in_memory());
+            Ok((configure(sqlite::establish(conn, migrations_dir)), ()))
+        })
+    }
+
+    impl Drop for SqliteTestDb {
+        fn drop(&mut self) {
+            // Close the connection before removing the file under it.
+            self.conn.take();
+            if let Some(ref path) = self.path {
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("Could not remove test database {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use std::env;
+    use postgres::{Connection, TlsMode};
+    use connection::postgres::{self, Postgres};
+    use super::*;
+
+    /// Environment variable holding the URL of the database test databases are created from.
+    pub const ADMIN_URL_VAR: &'static str = "SCURRY_TEST_POSTGRES_URL";
+
+    const DEFAULT_ADMIN_URL: &'static str = "postgres://localhost/scurry_test";
+
+    /// A Postgres database with a unique name, dropped again on drop.
+    pub struct PostgresTestDb {
+        conn: Option<Connection>,
+        admin_url: String,
+        name: String,
+    }
+
+    fn connect(url: &str) -> Result<Connection, ScurryError> {
+        Connection::connect(url, TlsMode::None).map_err(|e| ScurryError::Connection(Box::new(e)))
+    }
+
+    /// `url` with its database replaced by `name`, keeping any query parameters.
+    fn database_url(url: &str, name: &str) -> String {
+        let (base, params) = match url.find('?') {
+            Some(at) => (&url[..at], &url[at..]),
+            None => (url, ""),
+        };
+        let authority_end = base.find("://").map(|at| at + 3).unwrap_or(0);
+        let host = match base[authority_end..].find('/') {
+            Some(at) => &base[..authority_end + at],
+            None => base,
+        };
+        format!("{}/{}{}", host, name, params)
+    }
+
+    impl PostgresTestDb {
+        /// A new database migrated to the latest version.
+        pub fn new(migrations_dir: &str) -> Result<PostgresTestDb, ScurryError> {
+            PostgresTestDb::new_with(migrations_dir, DesiredVersion::Latest, |p| p)
+        }
+
+        /// A new database migrated to `version`, after `configure` has had a chance to register
+        /// placeholders, code migrations and the like.
+        pub fn new_with<F>(migrations_dir: &str,
+                           version: DesiredVersion,
+                           configure: F)
+                           -> Result<PostgresTestDb, ScurryError>
+            where F: FnOnce(Postgres) -> Postgres
+        {
+            let mut db = try!(PostgresTestDb::empty());
+            let conn = db.conn.take().unwrap();
+            let mut scurry = configure(postgres::establish(conn, migrations_dir));
+            try!(scurry.migrate(version));
+            db.conn = Some(scurry.take_connection());
+            Ok(db)
+        }
+
+        /// A new, unmigrated database.
+        pub fn empty() -> Result<PostgresTestDb, ScurryError> {
+            let admin_url = env::var(ADMIN_URL_VAR).unwrap_or(DEFAULT_ADMIN_URL.into());
+            let name = unique_name("scurry_test");
+            let admin = try!(connect(&admin_url));
+            try!(admin.batch_execute(&format!("CREATE DATABASE {};", name)));
+            // Own the database before connecting so a failure from here on still drops it.
+            let mut db = PostgresTestDb {
+                conn: None,
+                admin_url: admin_url,
+                name: name,
+            };
+            db.conn = Some(try!(connect(&db.url())));
+            Ok(db)
+        }
+
+        pub fn conn(&self) -> &Connection {
+            self.conn.as_ref().unwrap()
+        }
+
+        /// Name of the test database.
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        /// URL other connections to the test database can use.
+        pub fn url(&self) -> String {
+            database_url(&self.admin_url, &self.name)
+        }
+    }
+
+    /// Checks every version's schema against its snapshot in `snapshots_dir`, and the
+    /// upgrade from it to the latest version, using test databases.  `configure` is applied to
+    /// each connection before migrating.
+    pub fn check_postgres_upgrades<F>(migrations_dir: &str,
+                                      snapshots_dir: &str,
+                                      mode: SnapshotMode,
+                                      configure: F)
+                                      -> Result<Vec<String>, UpgradeFailure>
+        where F: Fn(Postgres) -> Postgres
+    {
+        check_upgrades(migrations_dir, snapshots_dir, mode, || {
+            let mut db = try!(PostgresTestDb::empty());
+            let conn = db.conn.take().unwrap();
+            Ok((configure(postgres::establish(conn, migrations_dir)), db))
+        })
+    }
+
+    impl Drop for PostgresTestDb {
+        fn drop(&mut self) {
+            // A database can't be dropped while anyone is connected to it.
+            self.conn.take();
+            let dropped = connect(&self.admin_url).and_then(|admin| {
+                admin.batch_execute(&format!("DROP DATABASE IF EXISTS {};", self.name))
+                    .map_err(ScurryError::from)
+            });
+            if let Err(e) = dropped {
+                warn!("Could not drop test database {}: {}", self.name, e);
+            }
+        }
+    }
+}
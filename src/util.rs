@@ -1,35 +1,153 @@
-use error::ScurryError;
-use std::path::Path;
+use error::{ScurryError, ConsistencyError};
+use std::path::{Path, PathBuf};
 use std::io::Error as IoError;
 use std::fs::{self, File};
 use std::io::prelude::*;
 use sha1;
-use versions::{Version, DesiredVersion};
+use versions::{Version, DesiredVersion, MigrationKind};
 use models::ScurryMetadata;
+use sql::{Statement, Dialect};
+use callbacks::{Callback, Callbacks};
+use placeholders::Placeholders;
+use listener::MigrationSummary;
+use scan::{ScanMode, ScanReport};
+use squash;
+use graph;
+use directives::Directives;
+use std::time::Duration;
 
-fn get_name_and_version(path: &Path) -> Result<(String, String), ScurryError> {
+const GUARD_DIRECTIVE: &'static str = "if";
+
+/// The query in a script's `-- scurry:if` directive, if it has one.  The migration only runs when
+/// the query's first column comes back true.
+pub fn guard(contents: &str) -> Option<String> {
+    Directives::parse(contents).get(GUARD_DIRECTIVE).map(|q| q.into())
+}
+
+fn invalid_file_name(path: &Path, reason: &str) -> ScurryError {
+    ScurryError::InvalidFileName {
+        path: path.to_string_lossy().into_owned(),
+        reason: reason.into(),
+    }
+}
+
+/// Splits a trailing dialect tag off a file stem: `0005__add_index.sqlite` becomes
+/// `("0005__add_index", Some(Dialect::Sqlite))`.
+pub fn split_dialect(stem: &str) -> (&str, Option<Dialect>) {
+    if let Some(dot) = stem.rfind('.') {
+        if let Some(dialect) = Dialect::from_tag(&stem[dot + 1..]) {
+            return (&stem[..dot], Some(dialect));
+        }
+    }
+    (stem, None)
+}
+
+fn get_name_and_version(path: &Path) -> Result<(String, String, Option<Dialect>), ScurryError> {
     let file_name = match path.file_stem() {
         Some(s) => s,
-        None => return Err(ScurryError::Parse("Could not determine file name".into())),
+        None => return Err(invalid_file_name(path, "could not determine file name")),
     };
     match file_name.to_str() {
-        None => Err(ScurryError::Parse("Could not get string path".into())),
+        None => Err(invalid_file_name(path, "file name is not valid UTF-8")),
         Some(s) => {
+            let (s, dialect) = split_dialect(s);
             let mut parts = s.split("__");
-            let version = match parts.next() {
+            let version: String = match parts.next() {
                 Some(s) => s.into(),
-                None => return Err(ScurryError::Parse("No string parts".into())),
+                None => return Err(invalid_file_name(path, "file name is empty")),
             };
             let remaining = parts.collect::<Vec<_>>();
             if remaining.is_empty() {
-                return Err(ScurryError::Parse("Invalid version and name; separate version and \
-                                               name with '__'"
-                    .into()));
+                return Err(invalid_file_name(path, "separate version and name with '__'"));
+            }
+            if version.is_empty() {
+                return Err(invalid_file_name(path, "version is empty"));
             }
             let name = remaining.join("__");
-            Ok((version, name))
+            if name.is_empty() {
+                return Err(invalid_file_name(path, "name is empty"));
+            }
+            Ok((version, name, dialect))
+        }
+    }
+}
+
+/// Wraps an error raised while applying `version` so callers can tell which migration failed.
+/// Errors that already name the failing statement are passed through untouched.
+pub fn migration_failed(version: &Version, cause: ScurryError) -> ScurryError {
+    match cause {
+        e @ ScurryError::MigrationFailed { .. } => e,
+        cause => {
+            ScurryError::MigrationFailed {
+                version: version.version.clone(),
+                name: version.name.clone(),
+                path: version.path.clone(),
+                statement_index: None,
+                span: None,
+                cause: Box::new(cause),
+            }
+        }
+    }
+}
+
+/// Wraps an error raised by one statement of `version`'s script.
+pub fn statement_failed(version: &Version,
+                        statement: &Statement,
+                        cause: ScurryError)
+                        -> ScurryError {
+    ScurryError::MigrationFailed {
+        version: version.version.clone(),
+        name: version.name.clone(),
+        path: version.path.clone(),
+        statement_index: Some(statement.index),
+        span: Some(statement.span),
+        cause: Box::new(cause),
+    }
+}
+
+/// Wraps an error raised by one statement of a callback script.
+pub fn callback_failed(callback: Callback,
+                       path: &str,
+                       statement: &Statement,
+                       cause: ScurryError)
+                       -> ScurryError {
+    ScurryError::CallbackFailed {
+        callback: callback,
+        path: path.into(),
+        statement_index: Some(statement.index),
+        span: Some(statement.span),
+        cause: Box::new(cause),
+    }
+}
+
+/// Substitutes placeholders into the script read from `path`.
+pub fn substitute(path: &str,
+                  contents: &str,
+                  placeholders: &Placeholders)
+                  -> Result<String, ScurryError> {
+    placeholders.substitute(contents).map_err(|names| {
+        ScurryError::MissingPlaceholders {
+            path: path.into(),
+            names: names,
+        }
+    })
+}
+
+/// Reads every script in `versions` with placeholders substituted, so a missing placeholder is
+/// reported before anything is executed.
+pub fn load_scripts(versions: &[&Version],
+                    placeholders: &Placeholders)
+                    -> Result<Vec<String>, ScurryError> {
+    let mut scripts = vec![];
+    for v in versions {
+        if v.kind == MigrationKind::Rust {
+            scripts.push(String::new());
+            continue;
         }
+        let contents = try!(get_file_contents(&v.path));
+        scripts.push(try!(substitute(&v.path, &contents, placeholders)));
     }
+    Ok(scripts)
 }
 
 pub fn get_file_contents(path: &str) -> Result<String, IoError> {
@@ -39,56 +157,268 @@ pub fn get_file_contents(path: &str) -> Result<String, IoError> {
     Ok(contents)
 }
 
-fn hash_file_contents(path: &Path) -> Result<String, IoError> {
+/// What is read from a migration file when scanning, before it is run.
+#[derive(Debug, Clone)]
+pub struct MigrationFile {
+    /// Taken over the script as written, before placeholder substitution, so changing a
+    /// placeholder's value doesn't look like the script was edited.
+    pub hash: String,
+    /// The versions the script squashes, if it is a baseline.
+    pub squashes: Vec<(String, String)>,
+    /// The versions named by its `depends-on` directives.
+    pub depends_on: Vec<String>,
+}
+
+pub fn read_migration(path: &Path) -> Result<MigrationFile, IoError> {
     let mut f = try!(File::open(path));
     let mut buffer = vec![];
     try!(f.read_to_end(&mut buffer));
     let mut m = sha1::Sha1::new();
     m.update(&buffer);
-    Ok(m.digest().to_string())
+    let contents = String::from_utf8_lossy(&buffer);
+    Ok(MigrationFile {
+        hash: m.digest().to_string(),
+        squashes: squash::squashed_versions(&contents),
+        depends_on: graph::dependencies(&contents),
+    })
+}
+
+fn is_sql_file(path: &Path) -> bool {
+    match path.extension() {
+        None => false,
+        Some(s) => s == "sql",
+    }
 }
 
-pub fn calculate_available_versions(migrations_dir: &str) -> Result<Vec<Version>, ScurryError> {
-    let all_paths = try!(fs::read_dir(migrations_dir));
-    let sql_files = all_paths.filter_map(|dirent| dirent.ok())
+/// A `.SQL` or `.Sql` file was almost certainly meant to be a migration.
+fn is_miscased_sql_file(path: &Path) -> bool {
+    match path.extension().and_then(|s| s.to_str()) {
+        None => false,
+        Some(s) => s != "sql" && s.eq_ignore_ascii_case("sql"),
+    }
+}
+
+pub fn path_string(path: &Path) -> Result<String, ScurryError> {
+    match path.to_str() {
+        Some(p) => Ok(p.into()),
+        None => Err(invalid_file_name(path, "path is not valid UTF-8")),
+    }
+}
+
+/// The directories migrations are read from.
+#[derive(Debug, Clone)]
+pub struct Locations {
+    pub dirs: Vec<String>,
+    /// Whether to descend into subdirectories of each directory.
+    pub recursive: bool,
+    /// What to do about unparsable or duplicated migration files.
+    pub mode: ScanMode,
+}
+
+impl Locations {
+    pub fn new(dir: &str) -> Locations {
+        Locations {
+            dirs: vec![dir.into()],
+            recursive: false,
+            mode: ScanMode::Strict,
+        }
+    }
+
+    pub fn add(&mut self, dir: &str) {
+        self.dirs.push(dir.into());
+    }
+
+    /// Every file under these locations, directory by directory, sorted within each.
+    pub fn files(&self) -> Result<Vec<PathBuf>, ScurryError> {
+        let mut files = vec![];
+        for dir in &self.dirs {
+            try!(walk(Path::new(dir), self.recursive, &mut files));
+        }
+        Ok(files)
+    }
+}
+
+fn walk(dir: &Path, recursive: bool, files: &mut Vec<PathBuf>) -> Result<(), ScurryError> {
+    let mut entries = try!(fs::read_dir(dir))
+        .filter_map(|dirent| dirent.ok())
         .map(|dirent| dirent.path())
-        .filter(|path| {
-            match path.extension() {
-                None => false,
-                Some(s) => s == "sql",
+        .collect::<Vec<_>>();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            if recursive {
+                try!(walk(&path, recursive, files));
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Finds the callback scripts (`beforeMigrate.sql` etc.) in the given locations that apply to
+/// `dialect`.  Within a directory, a dialect-tagged script replaces the plain one for the same
+/// callback; scripts from different directories all run, in location order.
+pub fn find_callbacks(locations: &Locations, dialect: Dialect) -> Result<Callbacks, ScurryError> {
+    let paths = try!(locations.files())
+        .into_iter()
+        .filter(|path| is_sql_file(path))
+        .collect::<Vec<_>>();
+    let found = paths.iter()
+        .filter_map(|path| Callback::from_path(path).map(|(c, d)| (c, d, path)))
+        .collect::<Vec<_>>();
+    let mut callbacks = Callbacks::default();
+    for &(callback, tag, path) in &found {
+        let wanted = match tag {
+            Some(d) => d == dialect,
+            None => {
+                !found.iter().any(|&(c, d, p)| {
+                    c == callback && d == Some(dialect) && p.parent() == path.parent()
+                })
             }
-        });
-    let mut res = vec![];
-    for file in sql_files {
-        let hash = try!(hash_file_contents(&file));
-        let (version, name) = try!(get_name_and_version(&file));
-        let path = match file.to_str() {
-            Some(p) => p.into(),
-            None => return Err(ScurryError::Parse("couldn't get file path".into())),
         };
+        if wanted {
+            let path = try!(path_string(path));
+            let contents = try!(get_file_contents(&path));
+            callbacks.add(callback, path, contents);
+        }
+    }
+    Ok(callbacks)
+}
+
+/// Scans `locations` for migrations, collecting every problem found along the way rather than
+/// stopping at the first.  With a `dialect`, files tagged for another dialect are dropped and a
+/// tagged file replaces the plain one of the same version, so the hash recorded for a version is
+/// that of the variant the backend actually ran.  Without one, every variant is listed.
+pub fn scan(locations: &Locations,
+            dialect: Option<Dialect>)
+            -> Result<(Vec<Version>, ScanReport), ScurryError> {
+    let mut report = ScanReport::default();
+    let mut res: Vec<Version> = vec![];
+    for file in try!(locations.files()) {
+        let path = try!(path_string(&file));
+        if !is_sql_file(&file) {
+            if is_miscased_sql_file(&file) {
+                report.unparsable.push((path, "extension must be lowercase '.sql'".into()));
+            } else {
+                report.ignored.push(path);
+            }
+            continue;
+        }
+        if Callback::from_path(&file).is_some() {
+            continue;
+        }
+        let (version, name, tag) = match get_name_and_version(&file) {
+            Ok(parsed) => parsed,
+            Err(ScurryError::InvalidFileName { reason, .. }) => {
+                report.unparsable.push((path, reason));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(existing) = res.iter().find(|v| v.version == version && v.dialect == tag) {
+            match report.duplicates.iter_mut().find(|&&mut (ref v, _)| v == &version) {
+                Some(&mut (_, ref mut paths)) => paths.push(path),
+                None => {
+                    report.duplicates.push((version, vec![existing.path.clone(), path]));
+                }
+            }
+            continue;
+        }
+        let file = try!(read_migration(&file));
         res.push(Version {
             path: path,
             name: name,
-            hash: hash,
+            hash: file.hash,
             version: version,
+            kind: MigrationKind::Sql,
+            dialect: tag,
+            squashes: file.squashes,
+            depends_on: file.depends_on,
+        });
+    }
+    if let Some(d) = dialect {
+        let tagged = res.iter()
+            .filter(|v| v.dialect == Some(d))
+            .map(|v| v.version.clone())
+            .collect::<Vec<_>>();
+        res.retain(|v| match v.dialect {
+            Some(other) => other == d,
+            None => !tagged.contains(&v.version),
         });
     }
     res.sort();
-    Ok(res)
+    Ok((res, report))
+}
+
+/// Lists the migrations in `locations` for `dialect` (see `scan`).  Unparsable file names and
+/// duplicated versions fail the scan unless the locations are in `ScanMode::Warn`, in which case
+/// they are logged and skipped.
+pub fn calculate_available_versions(locations: &Locations,
+                                    dialect: Option<Dialect>)
+                                    -> Result<Vec<Version>, ScurryError> {
+    let (versions, report) = try!(scan(locations, dialect));
+    if report.has_errors() {
+        match locations.mode {
+            ScanMode::Strict => return Err(ScurryError::InvalidMigrations(report)),
+            ScanMode::Warn => {
+                for line in report.to_string().lines() {
+                    warn!("{}", line);
+                }
+            }
+        }
+    }
+    Ok(versions)
+}
+
+/// Describes a migration implemented in Rust.  Without an explicit `checksum` one is derived
+/// from the version and name, so edits to the code itself go unnoticed by consistency checks.
+pub fn code_version(version: &str, name: &str, checksum: Option<&str>) -> Version {
+    let hash = match checksum {
+        Some(c) => c.into(),
+        None => {
+            let mut m = sha1::Sha1::new();
+            m.update(format!("{}__{}", version, name).as_bytes());
+            m.digest().to_string()
+        }
+    };
+    Version {
+        path: format!("rust:{}__{}", version, name),
+        name: name.into(),
+        hash: hash,
+        version: version.into(),
+        kind: MigrationKind::Rust,
+        dialect: None,
+        squashes: vec![],
+        depends_on: vec![],
+    }
 }
 
+/// Interleaves Rust migrations with those found on disk, in dependency order (see
+/// `graph::order`).
+pub fn merge_versions(mut available: Vec<Version>,
+                      code: Vec<Version>)
+                      -> Result<Vec<Version>, ScurryError> {
+    for v in code {
+        if let Some(existing) = available.iter().find(|a| a.version == v.version) {
+            return Err(ScurryError::DuplicateVersion {
+                version: v.version.clone(),
+                paths: vec![existing.path.clone(), v.path.clone()],
+            });
+        }
+        available.push(v);
+    }
+    graph::order(available)
+}
+
+/// The migrations still to run to reach `desired`, in the order to run them.  `available` must
+/// already be in dependency order.  A specific version takes in every version up to it along
+/// with whatever those depend on.
 pub fn choose_upgrade_path<'a>(available: &'a [Version],
-                               installed: &Option<&ScurryMetadata>,
+                               installed: &[ScurryMetadata],
                                desired: &DesiredVersion)
                                -> Vec<&'a Version> {
-    available.iter()
-        .filter(|v| {
-            if let Some(ref installed_vers) = *installed {
-                &v.version > &installed_vers.script_version
-            } else {
-                true
-            }
-        })
+    let mut wanted = available.iter()
         .filter(|v| {
             if let DesiredVersion::Specific(ref s) = *desired {
                 &v.version <= s
@@ -96,32 +426,114 @@ pub fn choose_upgrade_path<'a>(available: &'a [Version],
                 true
             }
         })
+        .collect::<Vec<_>>();
+    graph::with_ancestors(available, &mut wanted);
+    available.iter()
+        .filter(|v| wanted.iter().any(|w| w.version == v.version))
+        .filter(|v| !installed.iter().any(|i| i.script_version == v.version))
         .collect::<Vec<_>>()
+}
+
+/// The available versions that are installed, i.e. what a fresh database would have to run to
+/// end up where `installed` is.
+pub fn applied_versions<'a>(available: &'a [Version],
+                            installed: &[ScurryMetadata])
+                            -> Vec<&'a Version> {
+    available.iter()
+        .filter(|v| installed.iter().any(|i| i.script_version == v.version))
+        .collect()
+}
+
+/// Describes a finished run that started at `installed` and applied `applied`.
+pub fn summarize(installed: &Option<&ScurryMetadata>,
+                 applied: &[&Version],
+                 duration: Duration)
+                 -> MigrationSummary {
+    let from_version = installed.map(|m| m.script_version.clone());
+    let to_version = match applied.last() {
+        Some(v) => Some(v.version.clone()),
+        None => from_version.clone(),
+    };
+    MigrationSummary {
+        from_version: from_version,
+        to_version: to_version,
+        applied: applied.iter().map(|v| v.version.clone()).collect(),
+        duration: duration,
+    }
+}
 
+/// The history `installed` is expected to have.  A baseline stands in for the migrations it
+/// squashed only on databases that were created from it; databases that ran the original
+/// migrations are checked against those instead.
+fn expected_history(available: &[Version], installed: &[ScurryMetadata]) -> Vec<Version> {
+    let mut expected = vec![];
+    for v in available {
+        let predates_baseline = v.squashes
+            .first()
+            .map(|&(ref first, _)| installed.iter().any(|i| &i.script_version == first))
+            .unwrap_or(false);
+        if !predates_baseline {
+            expected.push(v.clone());
+            continue;
+        }
+        let mut previous: Option<&String> = None;
+        for &(ref version, ref hash) in &v.squashes {
+            let mut squashed = v.clone();
+            squashed.version = version.clone();
+            squashed.hash = hash.clone();
+            squashed.squashes = vec![];
+            if let Some(p) = previous {
+                squashed.depends_on = vec![p.clone()];
+            }
+            previous = Some(version);
+            expected.push(squashed);
+        }
+    }
+    expected
 }
 
+/// Checks that every installed version is still available with the same hash, and that nothing
+/// was installed without the versions it depends on.  Versions still to be applied are fine
+/// wherever they sit, as long as nothing installed depends on them.
 pub fn verify_common_history(available: &[Version],
                              installed: &[ScurryMetadata])
                              -> Result<(), ScurryError> {
-    let mut avail = available.iter();
+    let expected = expected_history(available, installed);
     for i in installed {
-        if let Some(v) = avail.next() {
-            if &v.version != &i.script_version {
-                return Err(ScurryError::Consistency(format!("Version mismatch: {} != {}",
-                                                            &i.script_version,
-                                                            &v.version)));
+        let v = match expected.iter().find(|v| v.version == i.script_version) {
+            Some(v) => v,
+            // Schema is ahead of migrations
+            None => return Err(ConsistencyError::UnknownVersion(i.script_version.clone()).into()),
+        };
+        if &i.script_hash != &v.hash {
+            return Err(ConsistencyError::HashMismatch {
+                    version: i.script_version.clone(),
+                    installed: i.script_hash.clone(),
+                    available: v.hash.clone(),
+                }
+                .into());
+        }
+        for d in &v.depends_on {
+            if !installed.iter().any(|o| &o.script_version == d) {
+                return Err(ConsistencyError::MissingDependency {
+                        version: i.script_version.clone(),
+                        dependency: d.clone(),
+                    }
+                    .into());
             }
-            if &i.script_hash != &v.hash {
-                return Err(ScurryError::Consistency(format!("Version hash mismatch for version \
-                                                             {}: {} != {}",
-                                                            &i.script_version,
-                                                            &i.script_hash,
-                                                            &v.hash)));
+        }
+    }
+    if let Some(latest) = installed.last() {
+        for v in available {
+            let partway = v.squashes.iter().any(|&(ref s, _)| s == &latest.script_version) &&
+                          v.version != latest.script_version;
+            if partway {
+                return Err(ConsistencyError::IncompleteSquash {
+                        baseline: v.version.clone(),
+                        installed: latest.script_version.clone(),
+                    }
+                    .into());
             }
-        } else {
-            // Schema is ahead of migrations
-            return Err(ScurryError::Consistency(format!("Schema contains unknown version {}",
-                                                        &i.script_version)));
         }
     }
     Ok(())
@@ -131,6 +543,7 @@ pub fn verify_common_history(available: &[Version],
 pub enum HistoryDifferences {
     Missing(Version),
     HashMismatch(Version),
+    #[deprecated(note = "never produced: differing versions are reported as Missing")]
     VersionMismatch(Version),
 }
 
@@ -138,16 +551,14 @@ pub fn get_history_differences(available: &[Version],
                                installed: &[ScurryMetadata])
                                -> Vec<HistoryDifferences> {
     let mut results = vec![];
-    let mut inst = installed.iter();
-    for a in available {
-        if let Some(sm) = inst.next() {
-            if &sm.script_version != &a.version {
-                results.push(HistoryDifferences::VersionMismatch(a.clone()));
-            } else if &sm.script_hash != &a.hash {
-                results.push(HistoryDifferences::HashMismatch(a.clone()));
+    for a in expected_history(available, installed) {
+        match installed.iter().find(|sm| sm.script_version == a.version) {
+            Some(sm) => {
+                if &sm.script_hash != &a.hash {
+                    results.push(HistoryDifferences::HashMismatch(a));
+                }
             }
-        } else {
-            results.push(HistoryDifferences::Missing(a.clone()));
+            None => results.push(HistoryDifferences::Missing(a)),
         }
     }
     results
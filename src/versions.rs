@@ -1,4 +1,14 @@
 use std::cmp::Ordering;
+use sql::Dialect;
+
+/// Where a migration's body comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationKind {
+    /// A `.sql` file in the migrations directory.
+    Sql,
+    /// Rust code registered on the connection.
+    Rust,
+}
 
 #[derive(Debug, Clone)]
 pub struct Version {
@@ -6,6 +16,15 @@ pub struct Version {
     pub name: String,
     pub hash: String,
     pub version: String,
+    pub kind: MigrationKind,
+    /// Set when the file only applies to one backend, e.g. `0005__add_index.sqlite.sql`.
+    pub dialect: Option<Dialect>,
+    /// For a baseline produced by squashing, the `(version, hash)` of every migration it
+    /// replaces, in order.
+    pub squashes: Vec<(String, String)>,
+    /// Versions this one has to be applied after.  Filled in with the previous version when the
+    /// script doesn't declare any (see `graph`).
+    pub depends_on: Vec<String>,
 }
 
 impl PartialEq for Version {
@@ -29,7 +48,7 @@ impl Ord for Version {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum DesiredVersion {
     Latest,
     Specific(String),